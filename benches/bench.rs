@@ -1,8 +1,8 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaChaRng;
 
-use sketch_duplicates::DuplicatesSketch;
+use sketch_duplicates::{DuplicatesSketch, Layout, SketchParams};
 
 fn sketch_benches(c: &mut Criterion) {
     let mut rng = ChaChaRng::seed_from_u64(42);
@@ -28,5 +28,79 @@ fn sketch_benches(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, sketch_benches);
+fn layout_benches(c: &mut Criterion) {
+    let mut rng = ChaChaRng::seed_from_u64(42);
+
+    // Short lines and a sketch much larger than the caches, so that memory access dominates
+    let size = 256 << 20;
+    let strings: Vec<_> = (0..100000)
+        .map(|_| {
+            let mut buf = vec![0; 16];
+            rng.fill(&mut buf[..]);
+            buf
+        })
+        .collect();
+
+    let mut insert = c.benchmark_group("layout_insert");
+    for &layout in &[Layout::Flat, Layout::Blocked] {
+        let params = SketchParams {
+            probes: 4,
+            layout,
+            ..Default::default()
+        };
+        let mut sketch = DuplicatesSketch::with_params(params, size);
+
+        insert.bench_function(BenchmarkId::from_parameter(format!("{:?}", layout)), |b| {
+            b.iter(|| strings.iter().for_each(|buf| sketch.insert(buf)))
+        });
+    }
+    insert.finish();
+
+    let mut filter = c.benchmark_group("layout_filter");
+    for &layout in &[Layout::Flat, Layout::Blocked] {
+        let params = SketchParams {
+            probes: 4,
+            layout,
+            ..Default::default()
+        };
+        let mut sketch = DuplicatesSketch::with_params(params, size);
+        strings.iter().for_each(|buf| sketch.insert(buf));
+
+        filter.bench_function(BenchmarkId::from_parameter(format!("{:?}", layout)), |b| {
+            b.iter(|| {
+                strings
+                    .iter()
+                    .filter(|buf| sketch.has_duplicate(buf))
+                    .count()
+            })
+        });
+    }
+    filter.finish();
+}
+
+fn merge_benches(c: &mut Criterion) {
+    let mut rng = ChaChaRng::seed_from_u64(42);
+
+    let mut merge = c.benchmark_group("merge");
+    for &counter_bits in &[2, 8] {
+        let params = SketchParams {
+            probes: 4,
+            counter_bits,
+            ..Default::default()
+        };
+        let mut sketch_a = DuplicatesSketch::with_params(params, 16 << 20);
+        let mut sketch_b = DuplicatesSketch::with_params(params, 16 << 20);
+        for _ in 0..100000 {
+            sketch_a.insert(&rng.gen::<[u8; 16]>());
+            sketch_b.insert(&rng.gen::<[u8; 16]>());
+        }
+
+        merge.bench_function(BenchmarkId::from_parameter(counter_bits), |b| {
+            b.iter(|| sketch_a.merge(&sketch_b))
+        });
+    }
+    merge.finish();
+}
+
+criterion_group!(benches, sketch_benches, layout_benches, merge_benches);
 criterion_main!(benches);
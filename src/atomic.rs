@@ -0,0 +1,231 @@
+//! Sketches that can be shared between threads.
+
+use crate::{DuplicatesSketch, DuplicatesSketchRef, HashKey, SketchParams, Words, WrongKey};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// A sketch that lines can be inserted into concurrently, through a shared reference.
+///
+/// With 2-bit counters or counting sketches, inserting lines from any number of threads gives
+/// exactly the same sketch as inserting them into a `DuplicatesSketch` one by one. Other wider
+/// counters are incremented without conservative update, as that cannot be done atomically across
+/// probes. Counts are then still never underestimated, but overestimated somewhat more often.
+#[derive(Debug)]
+pub struct AtomicDuplicatesSketch {
+    params: SketchParams,
+    key: Option<HashKey>,
+    key_fingerprint: Option<u64>,
+    words: Vec<AtomicU32>,
+}
+
+impl AtomicDuplicatesSketch {
+    pub fn with_params(params: SketchParams, size: usize) -> AtomicDuplicatesSketch {
+        DuplicatesSketch::with_params(params, size).into()
+    }
+
+    /// Create a sketch hashing lines keyed by `key`.
+    pub fn with_key(params: SketchParams, key: HashKey, size: usize) -> AtomicDuplicatesSketch {
+        DuplicatesSketch::with_key(params, key, size).into()
+    }
+
+    /// Fingerprint of the key the sketch was built with, if any.
+    pub fn key_fingerprint(&self) -> Option<u64> {
+        self.key_fingerprint
+    }
+
+    /// Set the key of a keyed sketch, see `DuplicatesSketch::set_key`.
+    pub fn set_key(&mut self, key: HashKey) -> Result<(), WrongKey> {
+        if self.key_fingerprint != Some(key.fingerprint()) {
+            return Err(WrongKey);
+        }
+
+        self.key = Some(key);
+        Ok(())
+    }
+
+    pub fn params(&self) -> SketchParams {
+        self.params
+    }
+
+    pub fn counter_bits(&self) -> u32 {
+        self.params.counter_bits
+    }
+
+    /// See `DuplicatesSketch::max_count`.
+    pub fn max_count(&self) -> u32 {
+        self.params.max_count()
+    }
+
+    /// Borrow the sketch for queries. Lines inserted concurrently may or may not be seen.
+    pub fn as_sketch_ref(&self) -> DuplicatesSketchRef<'_> {
+        DuplicatesSketchRef {
+            params: self.params,
+            key: self.key,
+            key_fingerprint: self.key_fingerprint,
+            words: Words::Atomic(&self.words),
+        }
+    }
+
+    pub fn into_sketch(self) -> DuplicatesSketch {
+        self.into()
+    }
+
+    /// Count an occurrence of `buf`.
+    ///
+    /// Panics if the sketch is keyed and its key has not been set, see `set_key`.
+    #[inline]
+    pub fn insert(&self, buf: &[u8]) {
+        let counter_bits = self.params.counter_bits;
+        let mask = self.params.counter_mask();
+
+        for (word_ix, bit_ix) in self.as_sketch_ref().probe_iter(buf) {
+            let word = &self.words[word_ix];
+            if counter_bits == 2 {
+                // Whichever insert sets the low bit first counts the first occurrence, all others
+                // set the high bit
+                let old = word.fetch_or(1 << bit_ix, Ordering::Relaxed);
+                if old & 1 << bit_ix != 0 {
+                    word.fetch_or(2 << bit_ix, Ordering::Relaxed);
+                }
+            } else {
+                // Fails only when the counter is saturated
+                let _ = word.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |word| {
+                    if word >> bit_ix & mask == mask {
+                        None
+                    } else {
+                        Some(word + (1 << bit_ix))
+                    }
+                });
+            }
+        }
+    }
+
+    #[inline]
+    pub fn has_duplicate(&self, buf: &[u8]) -> bool {
+        self.as_sketch_ref().has_duplicate(buf)
+    }
+
+    /// See `DuplicatesSketchRef::contains`.
+    #[inline]
+    pub fn contains(&self, buf: &[u8]) -> bool {
+        self.as_sketch_ref().contains(buf)
+    }
+
+    /// See `DuplicatesSketchRef::is_certainly_unique`.
+    #[inline]
+    pub fn is_certainly_unique(&self, buf: &[u8]) -> bool {
+        self.as_sketch_ref().is_certainly_unique(buf)
+    }
+
+    /// See `DuplicatesSketchRef::has_count_at_least`.
+    #[inline]
+    pub fn has_count_at_least(&self, buf: &[u8], k: u32) -> bool {
+        self.as_sketch_ref().has_count_at_least(buf, k)
+    }
+
+    /// See `DuplicatesSketchRef::has_count_at_most`.
+    #[inline]
+    pub fn has_count_at_most(&self, buf: &[u8], k: u32) -> bool {
+        self.as_sketch_ref().has_count_at_most(buf, k)
+    }
+
+    /// See `DuplicatesSketchRef::estimate_count`.
+    #[inline]
+    pub fn estimate_count(&self, buf: &[u8]) -> u32 {
+        self.as_sketch_ref().estimate_count(buf)
+    }
+}
+
+impl From<DuplicatesSketch> for AtomicDuplicatesSketch {
+    fn from(sketch: DuplicatesSketch) -> AtomicDuplicatesSketch {
+        AtomicDuplicatesSketch {
+            params: sketch.params,
+            key: sketch.key,
+            key_fingerprint: sketch.key_fingerprint,
+            words: sketch.words.into_iter().map(AtomicU32::new).collect(),
+        }
+    }
+}
+
+impl From<AtomicDuplicatesSketch> for DuplicatesSketch {
+    fn from(sketch: AtomicDuplicatesSketch) -> DuplicatesSketch {
+        DuplicatesSketch {
+            params: sketch.params,
+            key: sketch.key,
+            key_fingerprint: sketch.key_fingerprint,
+            words: sketch
+                .words
+                .into_iter()
+                .map(AtomicU32::into_inner)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{check_counts, duplicated_bufs, sketch_params};
+    use crate::Indexing;
+    use proptest::prelude::*;
+    use std::thread;
+
+    #[test]
+    fn threads() {
+        let params = SketchParams {
+            probes: 4,
+            indexing: Indexing::FastRange,
+            ..Default::default()
+        };
+        let lines: Vec<_> = (0..10000u32).map(|i| (i % 7000).to_le_bytes()).collect();
+
+        let atomic = AtomicDuplicatesSketch::with_params(params, 12345);
+        thread::scope(|scope| {
+            for chunk in lines.chunks(1000) {
+                let atomic = &atomic;
+                scope.spawn(move || chunk.iter().for_each(|line| atomic.insert(line)));
+            }
+        });
+
+        let mut sketch = DuplicatesSketch::with_params(params, 12345);
+        lines.iter().for_each(|line| sketch.insert(line));
+        assert_eq!(atomic.into_sketch(), sketch);
+    }
+
+    proptest! {
+        #[test]
+        fn insert_identical(bufs in duplicated_bufs(), probes in 1..8u32) {
+            let atomic: AtomicDuplicatesSketch = DuplicatesSketch::new(probes, 1024).into();
+            let mut sketch = DuplicatesSketch::new(probes, 1024);
+            for buf in &bufs {
+                atomic.insert(buf);
+                sketch.insert(buf);
+            }
+
+            prop_assert_eq!(atomic.into_sketch(), sketch);
+        }
+
+        #[test]
+        fn insert_counting(bufs in duplicated_bufs(), params in sketch_params()) {
+            let params = SketchParams {
+                counting: params.counter_bits > 2,
+                ..params
+            };
+            let atomic = AtomicDuplicatesSketch::with_params(params, 1024);
+            let mut sketch = DuplicatesSketch::with_params(params, 1024);
+            for buf in &bufs {
+                atomic.insert(buf);
+                sketch.insert(buf);
+            }
+
+            prop_assert_eq!(atomic.into_sketch(), sketch);
+        }
+
+        #[test]
+        fn insert_counts(bufs in duplicated_bufs(), params in sketch_params()) {
+            let atomic = AtomicDuplicatesSketch::with_params(params, 1024);
+            bufs.iter().for_each(|buf| atomic.insert(buf));
+
+            check_counts(&atomic.into_sketch(), &bufs)?;
+        }
+    }
+}
@@ -0,0 +1,931 @@
+//! Serialization of sketches.
+//!
+//! A serialized sketch starts with a header of little-endian fields:
+//!
+//! | Offset | Size | Field                                             |
+//! |--------|------|---------------------------------------------------|
+//! | 0      | 8    | Magic bytes, `\x89SKETCH\n`                       |
+//! | 8      | 2    | Format version, currently 1                       |
+//! | 10     | 1    | Bits per counter                                  |
+//! | 11     | 1    | Layout: 0 for flat, 1 for blocked                 |
+//! | 12     | 1    | Hash function: 0 for metro, 1 for xxh3, 2 for sip |
+//! | 13     | 1    | Flags: bit 0 keyed, 1 compressed, 2 counting      |
+//! | 14     | 1    | Indexing: 0 for mask, 1 for fastrange             |
+//! | 15     | 1    | Reserved, zero                                    |
+//! | 16     | 4    | Number of probes                                  |
+//! | 20     | 4    | Reserved, zero                                    |
+//! | 24     | 8    | Key fingerprint, zero for sketches without key    |
+//! | 32     | 8    | Number of words                                   |
+//!
+//! The header is followed by the words of the sketch as `u32`s, and an XXH3-64 checksum of
+//! everything after the magic bytes.
+//!
+//! In compressed sketches, the words are instead written as runs. Each run is a `u32` number of
+//! zero words, a `u32` number of literal words, and the literal words themselves.
+//!
+//! Sketches written by earlier versions have no magic bytes, and start with a `u32` holding the
+//! number of probes in its lower 24 bits, and parameters in bits 24 to 29. Bits 30 and 31 are never
+//! set, which tells them apart from the magic bytes. These can still be read.
+
+use crate::{
+    DuplicatesSketch, DuplicatesSketchRef, HashFunction, Indexing, Layout, SketchParams, Word,
+    Words, COUNTER_BITS, MAX_PROBES,
+};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    convert::TryFrom,
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+    mem::{size_of, size_of_val},
+};
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+const MAGIC: [u8; 8] = *b"\x89SKETCH\n";
+const VERSION: u16 = 1;
+const KEYED_FLAG: u8 = 1;
+const COMPRESSED_FLAG: u8 = 2;
+const COUNTING_FLAG: u8 = 4;
+/// Bytes in the header following the magic bytes
+const HEADER_LEN: usize = 32;
+
+/// Words written at a time
+const CHUNK_WORDS: usize = 8192;
+
+/// Shortest run of zero words that ends a run of literal words when compressing. Each run costs
+/// two words, so shorter runs of zeros are cheaper to write as literals.
+const MIN_ZERO_RUN: usize = 3;
+
+const LEGACY_PROBES_MASK: u32 = 0x00ffffff;
+const LEGACY_COUNTER_BITS_SHIFT: u32 = 24;
+const LEGACY_COUNTER_BITS_MASK: u32 = 0b11;
+const LEGACY_BLOCKED_BIT: u32 = 1 << 26;
+const LEGACY_HASH_SHIFT: u32 = 27;
+const LEGACY_HASH_MASK: u32 = 0b11;
+/// Keyed legacy sketches have the fingerprint of their key following the leading `u32`
+const LEGACY_KEYED_BIT: u32 = 1 << 29;
+const LEGACY_UNUSED_BITS: u32 = 0b11 << 30;
+
+/// Why a sketch could not be read.
+#[derive(Debug)]
+pub enum DeserializeError {
+    /// Reading the input failed.
+    Io(io::Error),
+    /// The input ended in the middle of a sketch.
+    Truncated,
+    /// The input is not a sketch.
+    NotASketch,
+    /// The sketch was written in a newer format version.
+    UnsupportedVersion(u16),
+    /// A field of the sketch header holds an invalid value.
+    InvalidField(&'static str),
+    /// The counters of the sketch take up more bytes than allowed.
+    TooLarge { bytes: u64, limit: u64 },
+    /// The sketch does not match its checksum.
+    ChecksumMismatch,
+    /// The runs of a compressed sketch do not add up to its number of words.
+    InvalidRuns,
+    /// Compressed sketches cannot be borrowed by `DuplicatesSketchRef::from_bytes`.
+    Compressed,
+}
+
+impl From<io::Error> for DeserializeError {
+    fn from(error: io::Error) -> DeserializeError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => DeserializeError::Truncated,
+            _ => DeserializeError::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeserializeError::Io(error) => write!(f, "Failed to read sketch: {}", error),
+            DeserializeError::Truncated => write!(f, "Sketch is truncated"),
+            DeserializeError::NotASketch => write!(f, "Not a sketch"),
+            DeserializeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported sketch format version {}", version)
+            }
+            DeserializeError::InvalidField(field) => {
+                write!(f, "Invalid {} in sketch header", field)
+            }
+            DeserializeError::TooLarge { bytes, limit } => write!(
+                f,
+                "Sketch of {} bytes is larger than the limit of {} bytes",
+                bytes, limit
+            ),
+            DeserializeError::ChecksumMismatch => write!(f, "Sketch checksum does not match"),
+            DeserializeError::InvalidRuns => write!(f, "Invalid runs in compressed sketch"),
+            DeserializeError::Compressed => {
+                write!(f, "Compressed sketches must be read into memory")
+            }
+        }
+    }
+}
+
+impl Error for DeserializeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DeserializeError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+fn hash_id(hash: HashFunction) -> u8 {
+    match hash {
+        HashFunction::Metro => 0,
+        HashFunction::Xxh3 => 1,
+        HashFunction::Sip => 2,
+    }
+}
+
+fn hash_from_id(id: u32) -> Result<HashFunction, DeserializeError> {
+    match id {
+        0 => Ok(HashFunction::Metro),
+        1 => Ok(HashFunction::Xxh3),
+        2 => Ok(HashFunction::Sip),
+        _ => Err(DeserializeError::InvalidField("hash function")),
+    }
+}
+
+/// Reader or writer that checksums all bytes passing through it
+struct Checksummed<T> {
+    inner: T,
+    hasher: Xxh3,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Checksummed<T> {
+        Checksummed {
+            inner,
+            hasher: Xxh3::new(),
+        }
+    }
+
+    fn checksum(&self) -> u64 {
+        self.hasher.digest()
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn write_words(file: &mut impl Write, words: &[Word]) -> io::Result<()> {
+    let mut bytes = vec![0; CHUNK_WORDS * size_of::<Word>()];
+    for chunk in words.chunks(CHUNK_WORDS) {
+        let bytes = &mut bytes[..size_of_val(chunk)];
+        LittleEndian::write_u32_into(chunk, bytes);
+        file.write_all(bytes)?;
+    }
+    Ok(())
+}
+
+/// Split `words` into runs of zero words followed by literal words
+fn runs(words: &[Word]) -> impl Iterator<Item = (usize, &[Word])> {
+    let mut words = words;
+    let max_run = u32::MAX as usize;
+    std::iter::from_fn(move || {
+        if words.is_empty() {
+            return None;
+        }
+
+        let zeros = words
+            .iter()
+            .take(max_run)
+            .take_while(|&&word| word == 0)
+            .count();
+        words = &words[zeros..];
+
+        let mut literals = 0;
+        while literals < words.len().min(max_run) {
+            let rest = &words[literals..];
+            if rest.iter().take(MIN_ZERO_RUN).all(|&word| word == 0) {
+                break;
+            }
+            literals += 1;
+        }
+        let (literal, rest) = words.split_at(literals);
+        words = rest;
+
+        Some((zeros, literal))
+    })
+}
+
+impl DuplicatesSketch {
+    pub fn serialize(&self, file: impl Write) -> io::Result<()> {
+        self.serialize_with(file, false)
+    }
+
+    /// Same as `serialize`, but writes runs of zero words compactly, which makes sparse sketches
+    /// much smaller. Sketches that would not get smaller are written uncompressed.
+    ///
+    /// Compressed sketches can be read by `deserialize`, but not borrowed by
+    /// `DuplicatesSketchRef::from_bytes`.
+    pub fn serialize_compressed(&self, file: impl Write) -> io::Result<()> {
+        let compressed_len: usize = runs(&self.words)
+            .map(|(_, literal)| 2 + literal.len())
+            .sum();
+        self.serialize_with(file, compressed_len < self.words.len())
+    }
+
+    fn serialize_with(&self, mut file: impl Write, compress: bool) -> io::Result<()> {
+        let SketchParams {
+            probes,
+            counter_bits,
+            layout,
+            hash,
+            indexing,
+            counting,
+        } = self.params;
+
+        file.write_all(&MAGIC)?;
+
+        let mut file = Checksummed::new(file);
+        file.write_u16::<LittleEndian>(VERSION)?;
+        file.write_u8(counter_bits as u8)?;
+        file.write_u8(match layout {
+            Layout::Flat => 0,
+            Layout::Blocked => 1,
+        })?;
+        file.write_u8(hash_id(hash))?;
+        let mut flags = 0;
+        if self.key_fingerprint.is_some() {
+            flags |= KEYED_FLAG;
+        }
+        if compress {
+            flags |= COMPRESSED_FLAG;
+        }
+        if counting {
+            flags |= COUNTING_FLAG;
+        }
+        file.write_u8(flags)?;
+        file.write_u8(match indexing {
+            Indexing::Mask => 0,
+            Indexing::FastRange => 1,
+        })?;
+        file.write_u8(0)?;
+        file.write_u32::<LittleEndian>(probes)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u64::<LittleEndian>(self.key_fingerprint.unwrap_or(0))?;
+        file.write_u64::<LittleEndian>(self.words.len() as u64)?;
+
+        if compress {
+            for (zeros, literal) in runs(&self.words) {
+                file.write_u32::<LittleEndian>(zeros as u32)?;
+                file.write_u32::<LittleEndian>(literal.len() as u32)?;
+                write_words(&mut file, literal)?;
+            }
+        } else {
+            write_words(&mut file, &self.words)?;
+        }
+
+        let checksum = file.checksum();
+        file.inner.write_u64::<LittleEndian>(checksum)?;
+
+        Ok(())
+    }
+
+    /// Read a sketch written by `serialize`, or by earlier versions. Returns `None` at the end of
+    /// the input, so that concatenated sketches can be read one by one.
+    pub fn deserialize(file: impl Read) -> Result<Option<DuplicatesSketch>, DeserializeError> {
+        DuplicatesSketch::deserialize_with_limit(file, None)
+    }
+
+    /// Same as `deserialize`, but fails without allocating if the counters of the sketch take up
+    /// more than `max_bytes`.
+    ///
+    /// Without a limit, corrupt inputs can still not make this allocate memory that is never
+    /// filled, but may fail with an out of memory error. Compressed sketches expand to their full
+    /// size from a small input, so untrusted inputs should be read with a limit.
+    pub fn deserialize_with_limit(
+        mut file: impl Read,
+        max_bytes: Option<u64>,
+    ) -> Result<Option<DuplicatesSketch>, DeserializeError> {
+        // Only the end of the input before the first byte is a clean end
+        let mut magic = [0; 8];
+        loop {
+            match file.read(&mut magic[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        file.read_exact(&mut magic[1..4])?;
+
+        if magic[..4] != MAGIC[..4] {
+            let header = LittleEndian::read_u32(&magic[..4]);
+            return deserialize_legacy(header, file, max_bytes).map(Some);
+        }
+
+        file.read_exact(&mut magic[4..])?;
+        if magic != MAGIC {
+            return Err(DeserializeError::NotASketch);
+        }
+
+        let mut file = Checksummed::new(file);
+        let mut header = [0; HEADER_LEN];
+        file.read_exact(&mut header)?;
+        let header = parse_header(&header)?;
+        let words = read_words(&mut file, &header, max_bytes)?;
+
+        let checksum = file.checksum();
+        if file.inner.read_u64::<LittleEndian>()? != checksum {
+            return Err(DeserializeError::ChecksumMismatch);
+        }
+
+        Ok(Some(header.into_sketch(words)))
+    }
+}
+
+impl<'a> DuplicatesSketchRef<'a> {
+    /// Borrow the first sketch serialized in `bytes`, returning it together with the bytes
+    /// following it.
+    ///
+    /// The counters are read from `bytes` as needed, so this does not copy them. The checksum is
+    /// still verified, which reads all of them once.
+    pub fn from_bytes(
+        bytes: &'a [u8],
+    ) -> Result<(DuplicatesSketchRef<'a>, &'a [u8]), DeserializeError> {
+        let mut file = bytes;
+        let leading = file.read_u32::<LittleEndian>()?;
+
+        let (header, checksummed) = if leading.to_le_bytes() != MAGIC[..4] {
+            (read_legacy_header(leading, &mut file)?, None)
+        } else {
+            let mut magic = [0; 4];
+            file.read_exact(&mut magic)?;
+            if magic != MAGIC[4..] {
+                return Err(DeserializeError::NotASketch);
+            }
+
+            let start = bytes.len() - file.len();
+            let mut header = [0; HEADER_LEN];
+            file.read_exact(&mut header)?;
+            (parse_header(&header)?, Some(start))
+        };
+
+        if header.compressed {
+            return Err(DeserializeError::Compressed);
+        }
+
+        let len = validate(&header, None)? * size_of::<Word>();
+        if file.len() < len {
+            return Err(DeserializeError::Truncated);
+        }
+        let (words, mut rest) = file.split_at(len);
+
+        if let Some(start) = checksummed {
+            let end = bytes.len() - rest.len();
+            if rest.read_u64::<LittleEndian>()? != xxh3_64(&bytes[start..end]) {
+                return Err(DeserializeError::ChecksumMismatch);
+            }
+        }
+
+        let sketch = DuplicatesSketchRef {
+            params: header.params,
+            key: None,
+            key_fingerprint: header.key_fingerprint,
+            words: Words::LittleEndian(words),
+        };
+        Ok((sketch, rest))
+    }
+}
+
+/// Fields of a sketch header
+struct Header {
+    params: SketchParams,
+    key_fingerprint: Option<u64>,
+    len: u64,
+    compressed: bool,
+}
+
+impl Header {
+    fn into_sketch(self, words: Vec<Word>) -> DuplicatesSketch {
+        DuplicatesSketch {
+            params: self.params,
+            key: None,
+            key_fingerprint: self.key_fingerprint,
+            words,
+        }
+    }
+}
+
+/// Parse the header following the magic bytes
+fn parse_header(header: &[u8; HEADER_LEN]) -> Result<Header, DeserializeError> {
+    let mut header = &header[..];
+    let version = header.read_u16::<LittleEndian>()?;
+    if version != VERSION {
+        return Err(DeserializeError::UnsupportedVersion(version));
+    }
+
+    let counter_bits = u32::from(header.read_u8()?);
+    let layout = match header.read_u8()? {
+        0 => Layout::Flat,
+        1 => Layout::Blocked,
+        _ => return Err(DeserializeError::InvalidField("layout")),
+    };
+    let hash = hash_from_id(u32::from(header.read_u8()?))?;
+    let flags = header.read_u8()?;
+    if flags & !(KEYED_FLAG | COMPRESSED_FLAG | COUNTING_FLAG) != 0 {
+        return Err(DeserializeError::InvalidField("flags"));
+    }
+    let indexing = match header.read_u8()? {
+        0 => Indexing::Mask,
+        1 => Indexing::FastRange,
+        _ => return Err(DeserializeError::InvalidField("indexing")),
+    };
+    if header.read_u8()? != 0 {
+        return Err(DeserializeError::InvalidField("reserved"));
+    }
+    let probes = header.read_u32::<LittleEndian>()?;
+    if header.read_u32::<LittleEndian>()? != 0 {
+        return Err(DeserializeError::InvalidField("reserved"));
+    }
+    let key_fingerprint = match header.read_u64::<LittleEndian>()? {
+        fingerprint if flags & KEYED_FLAG != 0 => Some(fingerprint),
+        0 => None,
+        _ => return Err(DeserializeError::InvalidField("key fingerprint")),
+    };
+
+    Ok(Header {
+        params: SketchParams {
+            probes,
+            counter_bits,
+            layout,
+            hash,
+            indexing,
+            counting: flags & COUNTING_FLAG != 0,
+        },
+        key_fingerprint,
+        len: header.read_u64::<LittleEndian>()?,
+        compressed: flags & COMPRESSED_FLAG != 0,
+    })
+}
+
+/// Read the rest of a sketch in the format without magic bytes, given its leading `u32`
+fn deserialize_legacy(
+    header: u32,
+    mut file: impl Read,
+    max_bytes: Option<u64>,
+) -> Result<DuplicatesSketch, DeserializeError> {
+    let header = read_legacy_header(header, &mut file)?;
+    let words = read_words(&mut file, &header, max_bytes)?;
+    Ok(header.into_sketch(words))
+}
+
+/// Read the rest of a header without magic bytes, given its leading `u32`
+fn read_legacy_header(header: u32, file: &mut impl Read) -> Result<Header, DeserializeError> {
+    if header & LEGACY_UNUSED_BITS != 0 {
+        return Err(DeserializeError::NotASketch);
+    }
+
+    let params = SketchParams {
+        probes: header & LEGACY_PROBES_MASK,
+        counter_bits: 2 << (header >> LEGACY_COUNTER_BITS_SHIFT & LEGACY_COUNTER_BITS_MASK),
+        layout: if header & LEGACY_BLOCKED_BIT != 0 {
+            Layout::Blocked
+        } else {
+            Layout::Flat
+        },
+        hash: hash_from_id(header >> LEGACY_HASH_SHIFT & LEGACY_HASH_MASK)?,
+        indexing: Indexing::Mask,
+        counting: false,
+    };
+
+    let key_fingerprint = if header & LEGACY_KEYED_BIT != 0 {
+        Some(file.read_u64::<LittleEndian>()?)
+    } else {
+        None
+    };
+
+    Ok(Header {
+        params,
+        key_fingerprint,
+        len: file.read_u64::<LittleEndian>()?,
+        compressed: false,
+    })
+}
+
+/// Validate the parameters and length in `header`, returning the number of words.
+fn validate(header: &Header, max_bytes: Option<u64>) -> Result<usize, DeserializeError> {
+    let Header { params, len, .. } = *header;
+    if params.probes == 0 || params.probes > MAX_PROBES {
+        return Err(DeserializeError::InvalidField("probes"));
+    }
+
+    if !COUNTER_BITS.contains(&params.counter_bits) {
+        return Err(DeserializeError::InvalidField("counter bits"));
+    }
+
+    if params.counting && params.counter_bits == 2 {
+        return Err(DeserializeError::InvalidField("flags"));
+    }
+
+    let bytes = len.saturating_mul(size_of::<Word>() as u64);
+    if let Some(limit) = max_bytes {
+        if bytes > limit {
+            return Err(DeserializeError::TooLarge { bytes, limit });
+        }
+    }
+
+    // The size in bytes has to fit in memory, which also keeps the length from overflowing
+    let fits = len
+        .checked_mul(size_of::<Word>() as u64)
+        .is_some_and(|bytes| usize::try_from(bytes).is_ok());
+    match usize::try_from(len) {
+        Ok(len) if fits && params.is_valid_len(len) => Ok(len),
+        _ => Err(DeserializeError::InvalidField("number of words")),
+    }
+}
+
+/// Validate `header`, then read its words.
+///
+/// Memory is reserved up front, but only touched as words are read, so a length larger than the
+/// input costs no more than the input itself. Compressed sketches are the exception, as their runs
+/// of zeros legitimately expand to any length.
+fn read_words(
+    file: &mut impl Read,
+    header: &Header,
+    max_bytes: Option<u64>,
+) -> Result<Vec<Word>, DeserializeError> {
+    let len = validate(header, max_bytes)?;
+
+    let mut words = Vec::new();
+    words
+        .try_reserve_exact(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "Not enough memory for sketch"))?;
+
+    if header.compressed {
+        while words.len() < len {
+            let zeros = file.read_u32::<LittleEndian>()? as usize;
+            let literals = file.read_u32::<LittleEndian>()? as usize;
+            if zeros + literals == 0 || zeros + literals > len - words.len() {
+                return Err(DeserializeError::InvalidRuns);
+            }
+
+            words.resize(words.len() + zeros, 0);
+            read_literal_words(file, &mut words, literals)?;
+        }
+    } else {
+        read_literal_words(file, &mut words, len)?;
+    }
+
+    Ok(words)
+}
+
+/// Read `count` words, appending them to `words`
+fn read_literal_words(
+    file: &mut impl Read,
+    words: &mut Vec<Word>,
+    count: usize,
+) -> Result<(), DeserializeError> {
+    let end = words.len() + count;
+    let mut bytes = vec![0; CHUNK_WORDS * size_of::<Word>()];
+    while words.len() < end {
+        let chunk = (end - words.len()).min(CHUNK_WORDS) * size_of::<Word>();
+        file.read_exact(&mut bytes[..chunk])?;
+        words.extend(bytes[..chunk].chunks_exact(4).map(LittleEndian::read_u32));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+    use std::io::Cursor;
+
+    fn serialized(sketch: &DuplicatesSketch) -> Vec<u8> {
+        let mut buf = Vec::new();
+        sketch.serialize(Cursor::new(&mut buf)).unwrap();
+        buf
+    }
+
+    fn compressed(sketch: &DuplicatesSketch) -> Vec<u8> {
+        let mut buf = Vec::new();
+        sketch.serialize_compressed(Cursor::new(&mut buf)).unwrap();
+        buf
+    }
+
+    #[test]
+    fn legacy() {
+        let mut sketch = DuplicatesSketch::new(4, 1024);
+        sketch.insert(b"asdf");
+        sketch.insert(b"asdf");
+
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(4).unwrap();
+        buf.write_u64::<LittleEndian>(sketch.words.len() as u64)
+            .unwrap();
+        for &word in &sketch.words {
+            buf.write_u32::<LittleEndian>(word).unwrap();
+        }
+
+        let legacy = DuplicatesSketch::deserialize(Cursor::new(buf))
+            .unwrap()
+            .unwrap();
+        assert_eq!(legacy, sketch);
+    }
+
+    #[test]
+    fn legacy_any_len() {
+        let mut buf = legacy_header(4, 100);
+        buf.resize(buf.len() + 100 * size_of::<Word>(), 0);
+
+        let mut sketch = DuplicatesSketch::deserialize(Cursor::new(&buf))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sketch.words.len(), 100);
+        sketch.insert(b"asdf");
+        sketch.insert(b"asdf");
+        assert!(sketch.has_duplicate(b"asdf"));
+        assert!(!sketch.can_fold_to(200));
+
+        let trip = DuplicatesSketch::deserialize(Cursor::new(serialized(&sketch)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(trip, sketch);
+    }
+
+    #[test]
+    fn concatenated() {
+        let sketch = DuplicatesSketch::new(4, 1024);
+        let mut buf = serialized(&sketch);
+        buf.extend(serialized(&sketch));
+
+        let mut file = Cursor::new(buf);
+        assert_eq!(
+            DuplicatesSketch::deserialize(&mut file).unwrap(),
+            Some(sketch)
+        );
+        assert!(DuplicatesSketch::deserialize(&mut file).unwrap().is_some());
+        assert!(DuplicatesSketch::deserialize(&mut file).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut sketch = DuplicatesSketch::new(4, 1024);
+        sketch.insert(b"asdf");
+
+        // Every flipped bit is detected, either by the magic bytes, header validation or the
+        // checksum. Bits in the first four bytes can turn the header into a legacy header instead.
+        for buf in &[serialized(&sketch), compressed(&sketch)] {
+            for i in 32..buf.len() * 8 {
+                let mut corrupted = buf.clone();
+                corrupted[i / 8] ^= 1 << (i % 8);
+                assert!(DuplicatesSketchRef::from_bytes(&corrupted).is_err());
+                assert!(DuplicatesSketch::deserialize(Cursor::new(corrupted)).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = serialized(&DuplicatesSketch::new(4, 1024));
+        for len in 1..buf.len() {
+            assert!(matches!(
+                DuplicatesSketch::deserialize(Cursor::new(&buf[..len])),
+                Err(DeserializeError::Truncated)
+            ));
+            assert!(matches!(
+                DuplicatesSketchRef::from_bytes(&buf[..len]),
+                Err(DeserializeError::Truncated)
+            ));
+        }
+
+        let buf = compressed(&DuplicatesSketch::new(4, 1024));
+        for len in 1..buf.len() {
+            assert!(matches!(
+                DuplicatesSketch::deserialize(Cursor::new(&buf[..len])),
+                Err(DeserializeError::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn fastrange() {
+        let params = SketchParams {
+            indexing: Indexing::FastRange,
+            ..DuplicatesSketch::new(4, 1024).params()
+        };
+        let mut sketch = DuplicatesSketch::with_params(params, 1000);
+        sketch.insert(b"asdf");
+        sketch.insert(b"asdf");
+
+        let buf = serialized(&sketch);
+        assert_eq!(
+            DuplicatesSketch::deserialize(Cursor::new(&buf)).unwrap(),
+            Some(sketch)
+        );
+        let (borrowed, _) = DuplicatesSketchRef::from_bytes(&buf).unwrap();
+        assert!(borrowed.has_duplicate(b"asdf"));
+
+        let mut corrupted = buf;
+        corrupted[14] = 2;
+        assert!(matches!(
+            DuplicatesSketch::deserialize(Cursor::new(corrupted)),
+            Err(DeserializeError::InvalidField("indexing"))
+        ));
+    }
+
+    #[test]
+    fn counting() {
+        let params = SketchParams {
+            counting: true,
+            ..DuplicatesSketch::with_counter_bits(4, 8, 1024).params()
+        };
+        let mut sketch = DuplicatesSketch::with_params(params, 1024);
+        sketch.insert(b"asdf");
+
+        let buf = serialized(&sketch);
+        assert_eq!(buf[13], COUNTING_FLAG);
+        let mut deserialized = DuplicatesSketch::deserialize(Cursor::new(&buf))
+            .unwrap()
+            .unwrap();
+        assert!(deserialized.remove(b"asdf"));
+
+        // 2-bit counters cannot be counting
+        let mut corrupted = serialized(&DuplicatesSketch::new(4, 1024));
+        corrupted[13] = COUNTING_FLAG;
+        assert!(matches!(
+            DuplicatesSketch::deserialize(Cursor::new(corrupted)),
+            Err(DeserializeError::InvalidField("flags"))
+        ));
+    }
+
+    #[test]
+    fn compress() {
+        let mut sketch = DuplicatesSketch::with_counter_bits(4, 8, 1 << 16);
+        for i in 0..100u32 {
+            sketch.insert(&i.to_le_bytes());
+        }
+
+        let buf = compressed(&sketch);
+        assert!(buf.len() < serialized(&sketch).len() / 4);
+        assert_eq!(
+            DuplicatesSketch::deserialize(Cursor::new(&buf)).unwrap(),
+            Some(sketch)
+        );
+        assert!(matches!(
+            DuplicatesSketchRef::from_bytes(&buf),
+            Err(DeserializeError::Compressed)
+        ));
+
+        // Sketches without runs of zeros are written uncompressed
+        let mut sketch = DuplicatesSketch::new(1, 64);
+        sketch.words.iter_mut().for_each(|word| *word = 1);
+        assert_eq!(compressed(&sketch), serialized(&sketch));
+    }
+
+    #[test]
+    fn invalid_runs() {
+        let sketch = DuplicatesSketch::new(4, 64);
+        let buf = compressed(&sketch);
+
+        // Replace the single run of zeros with runs that do not add up to the number of words
+        for &(zeros, literals) in &[(0, 0), (17, 0), (8, 9)] {
+            let mut corrupted = buf[..MAGIC.len() + HEADER_LEN].to_vec();
+            corrupted.write_u32::<LittleEndian>(zeros).unwrap();
+            corrupted.write_u32::<LittleEndian>(literals).unwrap();
+            corrupted.resize(corrupted.len() + literals as usize * 4 + 8, 0);
+
+            assert!(matches!(
+                DuplicatesSketch::deserialize(Cursor::new(corrupted)),
+                Err(DeserializeError::InvalidRuns)
+            ));
+        }
+    }
+
+    #[test]
+    fn borrowed() {
+        let mut sketch = DuplicatesSketch::with_counter_bits(4, 8, 1024);
+        for line in &[&b"a"[..], b"b", b"b", b"c", b"c", b"c"] {
+            sketch.insert(line);
+        }
+
+        let mut buf = serialized(&sketch);
+        buf.extend(serialized(&DuplicatesSketch::new(2, 64)));
+
+        let (borrowed, rest) = DuplicatesSketchRef::from_bytes(&buf).unwrap();
+        assert_eq!(borrowed.to_sketch(), sketch);
+        for line in &[&b"a"[..], b"b", b"c", b"d"] {
+            assert_eq!(borrowed.estimate_count(line), sketch.estimate_count(line));
+        }
+
+        let (second, rest) = DuplicatesSketchRef::from_bytes(rest).unwrap();
+        assert_eq!(second.to_sketch(), DuplicatesSketch::new(2, 64));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn borrowed_legacy() {
+        let mut sketch = DuplicatesSketch::new(4, 1024);
+        sketch.insert(b"asdf");
+        sketch.insert(b"asdf");
+
+        let mut buf = legacy_header(4, sketch.words.len() as u64);
+        for &word in &sketch.words {
+            buf.write_u32::<LittleEndian>(word).unwrap();
+        }
+
+        let (borrowed, rest) = DuplicatesSketchRef::from_bytes(&buf).unwrap();
+        assert!(borrowed.has_duplicate(b"asdf"));
+        assert_eq!(borrowed.to_sketch(), sketch);
+        assert!(rest.is_empty());
+    }
+
+    fn legacy_header(header: u32, len: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(header).unwrap();
+        buf.write_u64::<LittleEndian>(len).unwrap();
+        buf
+    }
+
+    #[test]
+    fn invalid_fields() {
+        for &(header, len, field) in &[
+            (0, 256, "probes"),
+            (4, 0, "number of words"),
+            (4 | LEGACY_BLOCKED_BIT, 48, "number of words"),
+            (4 | LEGACY_BLOCKED_BIT, 8, "number of words"),
+            (4 | 3 << LEGACY_HASH_SHIFT, 256, "hash function"),
+        ] {
+            match DuplicatesSketch::deserialize(Cursor::new(legacy_header(header, len))) {
+                Err(DeserializeError::InvalidField(invalid)) => assert_eq!(invalid, field),
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let buf = legacy_header(4, 1 << 40);
+        assert!(matches!(
+            DuplicatesSketch::deserialize_with_limit(Cursor::new(&buf), Some(1 << 20)),
+            Err(DeserializeError::TooLarge { .. })
+        ));
+
+        // Without a limit, this fails once the input runs out, or cannot be allocated
+        assert!(matches!(
+            DuplicatesSketch::deserialize(Cursor::new(&buf)),
+            Err(DeserializeError::Truncated) | Err(DeserializeError::Io(_))
+        ));
+
+        let buf = serialized(&DuplicatesSketch::new(4, 1024));
+        assert!(DuplicatesSketch::deserialize_with_limit(Cursor::new(&buf), Some(1024)).is_ok());
+        assert!(DuplicatesSketch::deserialize_with_limit(Cursor::new(&buf), Some(1023)).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn random_bytes(buf in vec(any::<u8>(), 0..1000)) {
+            let _ = DuplicatesSketchRef::from_bytes(&buf);
+            let _ = DuplicatesSketch::deserialize_with_limit(Cursor::new(buf), Some(1 << 20));
+        }
+
+        #[test]
+        fn random_header(buf in vec(any::<u8>(), 0..1000)) {
+            let mut file = MAGIC.to_vec();
+            file.extend(buf);
+            let _ = DuplicatesSketch::deserialize(Cursor::new(file));
+        }
+
+        #[test]
+        fn random_corruption(corruption in vec((any::<prop::sample::Index>(), any::<u8>()), 1..10)) {
+            let mut sketch = DuplicatesSketch::new(4, 1024);
+            sketch.insert(b"asdf");
+
+            let mut buf = serialized(&sketch);
+            for (index, byte) in corruption {
+                let index = index.index(buf.len());
+                buf[index] = byte;
+            }
+
+            if let Ok(Some(sketch)) = DuplicatesSketch::deserialize(Cursor::new(buf)) {
+                // Anything accepted must be usable
+                sketch.has_duplicate(b"asdf");
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Hash functions for deriving the probes of a line.
+
+use metrohash::MetroHash128;
+use siphasher::sip128::{Hasher128, SipHasher24};
+use std::{error::Error, fmt, hash::Hasher, io};
+use xxhash_rust::xxh3::{xxh3_128, xxh3_128_with_seed};
+
+/// A 128-bit hash function used to find the counters probed for a line.
+pub trait SketchHasher {
+    /// Hash `buf` into two 64-bit halves.
+    fn hash128(&self, buf: &[u8]) -> (u64, u64);
+
+    /// Hash `buf` into two 64-bit halves, keyed by `key`.
+    fn hash128_keyed(&self, key: &HashKey, buf: &[u8]) -> (u64, u64);
+}
+
+/// Secret key for hashing lines.
+///
+/// Without a key, anyone can craft lines whose probes collide, or check whether a given line is in
+/// a sketch. Keyed sketches can only be queried by those knowing the key. Only a fingerprint of
+/// the key is stored in serialized sketches.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct HashKey {
+    key: [u8; 16],
+    /// The key reduced to 64 bits, for hash functions that do not take larger keys
+    seed: u64,
+}
+
+impl HashKey {
+    pub fn new(key: [u8; 16]) -> HashKey {
+        let mut hash_key = HashKey { key, seed: 0 };
+        hash_key.seed = hash_key.derive(b"sketch-duplicates key seed");
+        hash_key
+    }
+
+    /// Generate a key from the random number source of the operating system.
+    pub fn random() -> io::Result<HashKey> {
+        let mut key = [0; 16];
+        getrandom::getrandom(&mut key)?;
+        Ok(HashKey::new(key))
+    }
+
+    /// Parse a key from 32 hexadecimal digits.
+    pub fn from_hex(hex: &str) -> Option<HashKey> {
+        if hex.len() != 32 || !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let mut key = [0; 16];
+        for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+        }
+
+        Some(HashKey::new(key))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.key
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// A 64-bit fingerprint of the key, which can be stored without revealing the key.
+    pub fn fingerprint(&self) -> u64 {
+        self.derive(b"sketch-duplicates key fingerprint")
+    }
+
+    fn derive(&self, context: &[u8]) -> u64 {
+        let (k0, k1) = self.halves();
+        let mut hasher = SipHasher24::new_with_keys(k0, k1);
+        hasher.write(context);
+        hasher.finish()
+    }
+
+    fn halves(&self) -> (u64, u64) {
+        let mut k0 = [0; 8];
+        let mut k1 = [0; 8];
+        k0.copy_from_slice(&self.key[..8]);
+        k1.copy_from_slice(&self.key[8..]);
+        (u64::from_le_bytes(k0), u64::from_le_bytes(k1))
+    }
+}
+
+impl fmt::Debug for HashKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HashKey({:016x})", self.fingerprint())
+    }
+}
+
+/// The key given for a sketch is not the one it was built with.
+#[derive(Debug)]
+pub struct WrongKey;
+
+impl fmt::Display for WrongKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key does not match the key the sketch was built with")
+    }
+}
+
+impl Error for WrongKey {}
+
+/// MetroHash128. This is the default, and was the only hash function used by earlier versions.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Metro;
+
+impl SketchHasher for Metro {
+    #[inline]
+    fn hash128(&self, buf: &[u8]) -> (u64, u64) {
+        let mut hasher = MetroHash128::new();
+        hasher.write(buf);
+        hasher.finish128()
+    }
+
+    /// MetroHash128 only takes a 64-bit seed, so this uses a seed derived from the key.
+    #[inline]
+    fn hash128_keyed(&self, key: &HashKey, buf: &[u8]) -> (u64, u64) {
+        let mut hasher = MetroHash128::with_seed(key.seed);
+        hasher.write(buf);
+        hasher.finish128()
+    }
+}
+
+/// XXH3 with 128-bit output. Faster than MetroHash128 for long lines.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Xxh3;
+
+impl SketchHasher for Xxh3 {
+    #[inline]
+    fn hash128(&self, buf: &[u8]) -> (u64, u64) {
+        let hash = xxh3_128(buf);
+        (hash as u64, (hash >> 64) as u64)
+    }
+
+    /// XXH3 only takes a 64-bit seed, so this uses a seed derived from the key.
+    #[inline]
+    fn hash128_keyed(&self, key: &HashKey, buf: &[u8]) -> (u64, u64) {
+        let hash = xxh3_128_with_seed(buf, key.seed);
+        (hash as u64, (hash >> 64) as u64)
+    }
+}
+
+/// SipHash-2-4 with 128-bit output. Slower, but more robust against crafted inputs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sip;
+
+impl SketchHasher for Sip {
+    #[inline]
+    fn hash128(&self, buf: &[u8]) -> (u64, u64) {
+        let mut hasher = SipHasher24::new();
+        hasher.write(buf);
+        let hash = hasher.finish128();
+        (hash.h1, hash.h2)
+    }
+
+    /// SipHash uses the full 128-bit key, so only this resists attackers that can see output
+    /// produced using the key.
+    #[inline]
+    fn hash128_keyed(&self, key: &HashKey, buf: &[u8]) -> (u64, u64) {
+        let (k0, k1) = key.halves();
+        let mut hasher = SipHasher24::new_with_keys(k0, k1);
+        hasher.write(buf);
+        let hash = hasher.finish128();
+        (hash.h1, hash.h2)
+    }
+}
+
+/// The hash function of a sketch. Sketches can only be combined or compared when they use the
+/// same hash function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashFunction {
+    Metro,
+    Xxh3,
+    Sip,
+}
+
+impl HashFunction {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashFunction::Metro => "metro",
+            HashFunction::Xxh3 => "xxh3",
+            HashFunction::Sip => "sip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HashFunction> {
+        [HashFunction::Metro, HashFunction::Xxh3, HashFunction::Sip]
+            .iter()
+            .copied()
+            .find(|hash| hash.name() == name)
+    }
+}
+
+impl SketchHasher for HashFunction {
+    #[inline]
+    fn hash128(&self, buf: &[u8]) -> (u64, u64) {
+        match self {
+            HashFunction::Metro => Metro.hash128(buf),
+            HashFunction::Xxh3 => Xxh3.hash128(buf),
+            HashFunction::Sip => Sip.hash128(buf),
+        }
+    }
+
+    #[inline]
+    fn hash128_keyed(&self, key: &HashKey, buf: &[u8]) -> (u64, u64) {
+        match self {
+            HashFunction::Metro => Metro.hash128_keyed(key, buf),
+            HashFunction::Xxh3 => Xxh3.hash128_keyed(key, buf),
+            HashFunction::Sip => Sip.hash128_keyed(key, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        for &hash in &[HashFunction::Metro, HashFunction::Xxh3, HashFunction::Sip] {
+            assert_eq!(HashFunction::from_name(hash.name()), Some(hash));
+        }
+        assert_eq!(HashFunction::from_name("md5"), None);
+    }
+
+    #[test]
+    fn dispatch() {
+        let buf = b"asdf";
+        assert_eq!(HashFunction::Metro.hash128(buf), Metro.hash128(buf));
+        assert_eq!(HashFunction::Xxh3.hash128(buf), Xxh3.hash128(buf));
+        assert_eq!(HashFunction::Sip.hash128(buf), Sip.hash128(buf));
+        assert_ne!(Metro.hash128(buf), Xxh3.hash128(buf));
+        assert_ne!(Metro.hash128(buf), Sip.hash128(buf));
+    }
+
+    #[test]
+    fn keyed() {
+        let buf = b"asdf";
+        let key_a = HashKey::new([1; 16]);
+        let key_b = HashKey::new([2; 16]);
+        for &hash in &[HashFunction::Metro, HashFunction::Xxh3, HashFunction::Sip] {
+            assert_ne!(hash.hash128_keyed(&key_a, buf), hash.hash128(buf));
+            assert_ne!(
+                hash.hash128_keyed(&key_a, buf),
+                hash.hash128_keyed(&key_b, buf)
+            );
+        }
+        assert_ne!(key_a.fingerprint(), key_b.fingerprint());
+    }
+
+    #[test]
+    fn hex() {
+        let key = HashKey::random().unwrap();
+        assert_eq!(HashKey::from_hex(&key.to_hex()), Some(key));
+        assert_eq!(HashKey::from_hex("00"), None);
+        assert_eq!(HashKey::from_hex(&"g".repeat(32)), None);
+    }
+}
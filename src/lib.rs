@@ -1,104 +1,782 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use metrohash::MetroHash128;
+mod atomic;
+mod format;
+mod hash;
+mod merge;
+mod plan;
+mod stats;
+mod window;
+
+pub use atomic::AtomicDuplicatesSketch;
+pub use format::DeserializeError;
+pub use hash::{HashFunction, HashKey, Metro, Sip, SketchHasher, WrongKey, Xxh3};
+pub use plan::{false_positive_rate, plan_for_rate, plan_for_size, Plan};
+pub use stats::SketchStats;
+pub use window::WindowedSketch;
+
+use byteorder::{ByteOrder, LittleEndian};
 use std::{
-    hash::Hasher,
-    io,
-    io::{Read, Write},
     mem::size_of,
+    sync::atomic::{AtomicU32, Ordering},
 };
 
 type Word = u32;
 const WORD_BITS: u32 = 32;
-const WORD_LEN_BITS: usize = 5;
 const WORD_MASK: u32 = 0x55555555;
 
+/// Words in a 64 byte block of the blocked layout
+const BLOCK_WORDS: usize = 16;
+
+/// Largest number of probes a sketch can use.
+pub const MAX_PROBES: u32 = 0x00ffffff;
+
+/// Counter widths supported by sketches.
+pub const COUNTER_BITS: &[u32] = &[2, 4, 8, 16];
+
+/// How the counters probed for a line are spread over the sketch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Probes are spread over the whole sketch.
+    Flat,
+    /// All probes for a line fall within a single 64 byte block, so that each line costs at most
+    /// one cache miss. This is faster for large sketches, but has a somewhat higher false positive
+    /// rate than the flat layout.
+    Blocked,
+}
+
+/// How hashes are mapped to the words of a sketch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indexing {
+    /// Mask off the low bits of hashes. This needs a power of two number of words, or blocks in
+    /// the blocked layout, so sizes are rounded up. Sketches written before other sizes were
+    /// supported all use this.
+    Mask,
+    /// Map hashes to words with a multiply-shift range reduction, which works for any number of
+    /// words or blocks, so that sketches are exactly as large as asked for.
+    FastRange,
+}
+
+/// Map `hash` to the range `0..len` by taking the high 64 bits of their product
+#[inline]
+fn fastrange(hash: u64, len: usize) -> usize {
+    ((u128::from(hash) * len as u128) >> 64) as usize
+}
+
+/// Parameters of a sketch, apart from its size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SketchParams {
+    /// Number of counters probed for each line. Must be between 1 and `MAX_PROBES`.
+    pub probes: u32,
+    /// Bits per counter. Must be one of `COUNTER_BITS`.
+    pub counter_bits: u32,
+    pub layout: Layout,
+    pub hash: HashFunction,
+    pub indexing: Indexing,
+    /// Increment every probed counter on insert, instead of only the smallest ones, so that lines
+    /// can be removed again. This overestimates counts more often. Needs counters wider than 2
+    /// bits.
+    pub counting: bool,
+}
+
+/// The parameters of sketches written by earlier versions, with the default number of probes of
+/// the command line tool.
+impl Default for SketchParams {
+    fn default() -> SketchParams {
+        SketchParams {
+            probes: 2,
+            counter_bits: 2,
+            layout: Layout::Flat,
+            hash: HashFunction::Metro,
+            indexing: Indexing::Mask,
+            counting: false,
+        }
+    }
+}
+
+impl SketchParams {
+    /// Whether a sketch of `len` words can use these parameters
+    ///
+    /// Flat sketches with mask indexing are only ever built with a power of two words, but
+    /// earlier versions wrote sketches of any length, which masking still keeps in bounds.
+    pub(crate) fn is_valid_len(self, len: usize) -> bool {
+        let blocks = len / BLOCK_WORDS;
+        match (self.layout, self.indexing) {
+            (Layout::Flat, Indexing::Mask) => len > 0,
+            (Layout::Blocked, Indexing::Mask) => {
+                len.is_multiple_of(BLOCK_WORDS) && blocks.is_power_of_two()
+            }
+            (Layout::Flat, Indexing::FastRange) => len > 0,
+            (Layout::Blocked, Indexing::FastRange) => len.is_multiple_of(BLOCK_WORDS) && blocks > 0,
+        }
+    }
+
+    pub(crate) fn max_count(self) -> u32 {
+        if self.counter_bits == 2 {
+            2
+        } else {
+            self.counter_mask()
+        }
+    }
+
+    #[inline]
+    fn counter_mask(self) -> u32 {
+        (1 << self.counter_bits) - 1
+    }
+
+    /// The counter at `bit_ix` in `word`
+    #[inline]
+    fn counter(self, word: Word, bit_ix: u32) -> u32 {
+        (word >> bit_ix & self.counter_mask()).min(self.max_count())
+    }
+}
+
+/// Hash of a line, computed for sketches using a particular hash function and key.
+///
+/// Hashing is a large part of the cost of inserting a line, so it can be done ahead of time, for
+/// example on another thread, and reused for any number of sketches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineHash {
+    hash: HashFunction,
+    key_fingerprint: Option<u64>,
+    halves: (u64, u64),
+}
+
+/// Hashes lines for sketches using a particular hash function and key.
+#[derive(Debug, Clone, Copy)]
+pub struct LineHasher {
+    hash: HashFunction,
+    key: Option<HashKey>,
+    key_fingerprint: Option<u64>,
+}
+
+impl LineHasher {
+    /// Hash `buf` for the sketches this hasher is for.
+    ///
+    /// Panics if the sketch this hasher was taken from is keyed and its key had not been set, see
+    /// `DuplicatesSketch::set_key`.
+    #[inline]
+    pub fn hash_line(&self, buf: &[u8]) -> LineHash {
+        let halves = match self.key {
+            Some(ref key) => self.hash.hash128_keyed(key, buf),
+            None => {
+                assert!(self.key_fingerprint.is_none(), "Key of sketch is not set");
+                self.hash.hash128(buf)
+            }
+        };
+
+        LineHash {
+            hash: self.hash,
+            key_fingerprint: self.key_fingerprint,
+            halves,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct DuplicatesSketch {
-    probes: u32,
+    params: SketchParams,
+    key: Option<HashKey>,
+    key_fingerprint: Option<u64>,
     words: Vec<Word>,
 }
 
 impl DuplicatesSketch {
     pub fn new(probes: u32, size: usize) -> DuplicatesSketch {
-        assert!(probes > 0);
+        DuplicatesSketch::with_counter_bits(probes, 2, size)
+    }
+
+    /// Create a sketch with `counter_bits` wide counters, which must be one of `COUNTER_BITS`.
+    ///
+    /// Wider counters can count more occurrences of a line, but fit fewer counters in the same
+    /// size.
+    pub fn with_counter_bits(probes: u32, counter_bits: u32, size: usize) -> DuplicatesSketch {
+        DuplicatesSketch::with_params(
+            SketchParams {
+                probes,
+                counter_bits,
+                ..Default::default()
+            },
+            size,
+        )
+    }
+
+    pub fn with_params(params: SketchParams, size: usize) -> DuplicatesSketch {
+        assert!(params.probes > 0 && params.probes <= MAX_PROBES);
+        assert!(COUNTER_BITS.contains(&params.counter_bits));
+        assert!(!params.counting || params.counter_bits > 2);
 
         let size = size / size_of::<Word>();
-        let size = if size.is_power_of_two() {
-            size.next_power_of_two()
-        } else {
-            size
+        let blocks = (size / BLOCK_WORDS).max(1);
+        let size = match (params.layout, params.indexing) {
+            (Layout::Flat, Indexing::Mask) => size.max(1).next_power_of_two(),
+            (Layout::Blocked, Indexing::Mask) => blocks.next_power_of_two() * BLOCK_WORDS,
+            (Layout::Flat, Indexing::FastRange) => size.max(1),
+            (Layout::Blocked, Indexing::FastRange) => blocks * BLOCK_WORDS,
         };
 
         DuplicatesSketch {
-            probes,
+            params,
+            key: None,
+            key_fingerprint: None,
             words: vec![0; size],
         }
     }
 
+    /// Create a sketch hashing lines keyed by `key`.
+    pub fn with_key(params: SketchParams, key: HashKey, size: usize) -> DuplicatesSketch {
+        DuplicatesSketch {
+            key: Some(key),
+            key_fingerprint: Some(key.fingerprint()),
+            ..DuplicatesSketch::with_params(params, size)
+        }
+    }
+
+    /// Fingerprint of the key the sketch was built with, if any.
+    pub fn key_fingerprint(&self) -> Option<u64> {
+        self.key_fingerprint
+    }
+
+    /// Set the key of a keyed sketch. Deserialized sketches only hold a fingerprint of their key,
+    /// so it must be set before lines can be inserted or queried. Until then, those panic.
+    ///
+    /// Fails if `key` is not the key the sketch was built with, or the sketch is not keyed.
+    pub fn set_key(&mut self, key: HashKey) -> Result<(), WrongKey> {
+        if self.key_fingerprint != Some(key.fingerprint()) {
+            return Err(WrongKey);
+        }
+
+        self.key = Some(key);
+        Ok(())
+    }
+
+    pub fn params(&self) -> SketchParams {
+        self.params
+    }
+
+    pub fn counter_bits(&self) -> u32 {
+        self.params.counter_bits
+    }
+
+    /// The largest count the counters of this sketch can distinguish. Lines occurring more often
+    /// than this are counted as occurring exactly this many times.
+    ///
+    /// 2-bit counters only distinguish 0, 1 and 2 or more occurrences.
+    pub fn max_count(&self) -> u32 {
+        self.params.max_count()
+    }
+
+    /// Borrow the sketch for queries.
+    pub fn as_sketch_ref(&self) -> DuplicatesSketchRef<'_> {
+        DuplicatesSketchRef {
+            params: self.params,
+            key: self.key,
+            key_fingerprint: self.key_fingerprint,
+            words: Words::Native(&self.words),
+        }
+    }
+
+    #[inline]
+    fn counter(&self, word_ix: usize, bit_ix: u32) -> u32 {
+        self.params.counter(self.words[word_ix], bit_ix)
+    }
+
     pub fn is_compatible(&self, other: &DuplicatesSketch) -> bool {
-        self.probes == other.probes && self.words.len() == other.words.len()
+        self.params == other.params
+            && self.key_fingerprint == other.key_fingerprint
+            && self.words.len() == other.words.len()
     }
 
     pub fn merge(&mut self, other: &DuplicatesSketch) {
         assert!(self.is_compatible(other));
-        for (a, b) in self.words.iter_mut().zip(other.words.iter()) {
-            *a |= *b | (*a & WORD_MASK).wrapping_add(*b & WORD_MASK);
+
+        merge::merge_words(&mut self.words, &other.words, self.params.counter_bits);
+    }
+
+    /// Lower each counter to the counter of `other`, if that is smaller. A line is then counted as
+    /// often as it was in whichever sketch counted it least, which is never below the smaller of
+    /// its two counts.
+    pub fn intersect(&mut self, other: &DuplicatesSketch) {
+        assert!(self.is_compatible(other));
+
+        merge::intersect_words(&mut self.words, &other.words, self.params.counter_bits);
+    }
+
+    /// Subtract the counters of `other` from those of this sketch, stopping at zero.
+    ///
+    /// As `other` may overestimate counts, this may underestimate the difference, so lines
+    /// can be missed by `has_count_at_least` and `contains` afterwards, and `is_certainly_unique`
+    /// and `has_count_at_most` are no longer exact. Saturated counters also make differences of
+    /// large counts too small.
+    pub fn subtract(&mut self, other: &DuplicatesSketch) {
+        assert!(self.is_compatible(other));
+
+        merge::subtract_words(&mut self.words, &other.words, self.params.counter_bits);
+    }
+
+    /// Set all nonzero counters to 1, so that the sketch only tells which lines were inserted, and
+    /// not how often.
+    ///
+    /// Merging such sketches built from different sources counts each line at most once per
+    /// source, so that the count of a line estimates the number of sources it occurs in.
+    pub fn collapse_to_presence(&mut self) {
+        merge::presence_words(&mut self.words, self.params.counter_bits);
+    }
+
+    /// Size of the sketch in bytes.
+    pub fn size(&self) -> usize {
+        self.words.len() * size_of::<Word>()
+    }
+
+    /// Whether the sketch can be folded to `size` bytes, see `fold_to`.
+    pub fn can_fold_to(&self, size: usize) -> bool {
+        let len = size / size_of::<Word>();
+        size.is_multiple_of(size_of::<Word>())
+            && len > 0
+            && self.words.len().is_multiple_of(len)
+            && self.params.is_valid_len(len)
+            && (self.params.indexing == Indexing::FastRange
+                || (len.is_power_of_two() && self.words.len().is_power_of_two()))
+    }
+
+    /// The largest size of at most `max_size` bytes the sketch can be folded to, if any.
+    pub fn max_fold_size(&self, max_size: usize) -> Option<usize> {
+        let unit = match self.params.layout {
+            Layout::Flat => 1,
+            Layout::Blocked => BLOCK_WORDS,
+        };
+        let units = self.words.len() / unit;
+        let max_units = max_size / size_of::<Word>() / unit;
+
+        // Only whole factors of the number of units can be folded by
+        let folded_units = match self.params.indexing {
+            Indexing::Mask => (0..usize::BITS)
+                .map(|shift| units >> shift)
+                .find(|&folded| folded <= max_units),
+            Indexing::FastRange => (1..)
+                .take_while(|factor| factor * factor <= units)
+                .filter(|factor| units.is_multiple_of(*factor))
+                .flat_map(|factor| [factor, units / factor])
+                .filter(|&folded| folded <= max_units)
+                .max(),
+        }?;
+
+        let size = folded_units * unit * size_of::<Word>();
+        Some(size).filter(|&size| self.can_fold_to(size))
+    }
+
+    /// Shrink the sketch to `size` bytes, giving the sketch that inserting the same lines into a
+    /// sketch of that size would have, except that wider counters may count somewhat higher.
+    ///
+    /// The number of words, or blocks in the blocked layout, has to shrink by a whole factor. With
+    /// mask indexing, that factor is a power of two and a word maps to the same word modulo the
+    /// smaller size. With fastrange indexing, a run of `factor` consecutive words or blocks maps
+    /// to one.
+    pub fn fold_to(&mut self, size: usize) {
+        assert!(self.can_fold_to(size));
+
+        let len = size / size_of::<Word>();
+        let factor = self.words.len() / len;
+        let counter_bits = self.params.counter_bits;
+        match self.params.indexing {
+            Indexing::Mask => {
+                let (folded, rest) = self.words.split_at_mut(len);
+                for upper in rest.chunks_exact(len) {
+                    merge::merge_words(folded, upper, counter_bits);
+                }
+            }
+            Indexing::FastRange => {
+                let unit = match self.params.layout {
+                    Layout::Flat => 1,
+                    Layout::Blocked => BLOCK_WORDS,
+                };
+                // Each folded unit is written after all units folded into it were read
+                for ix in 0..len / unit {
+                    let first = ix * factor * unit;
+                    self.words.copy_within(first..first + unit, ix * unit);
+                    for j in 1..factor {
+                        let (folded, rest) = self.words.split_at_mut(first + j * unit);
+                        merge::merge_words(
+                            &mut folded[ix * unit..(ix + 1) * unit],
+                            &rest[..unit],
+                            counter_bits,
+                        );
+                    }
+                }
+            }
         }
+
+        self.words.truncate(len);
+        self.words.shrink_to_fit();
     }
 
+    /// Count an occurrence of `buf`.
+    ///
+    /// Counters wider than 2 bits use conservative update: only the probed counters holding the
+    /// smallest value are incremented, as the others already overestimate the count of `buf`.
+    /// 2-bit counters increment all probed counters, which keeps them identical to sketches
+    /// built before counter widths were configurable.
+    ///
+    /// Panics if the sketch is keyed and its key has not been set, see `set_key`.
     #[inline]
     pub fn insert(&mut self, buf: &[u8]) {
-        for (word_ix, bit_ix) in self.probe_iter(buf) {
-            let word = &mut self.words[word_ix];
-            *word |= (*word & 1 << bit_ix).wrapping_add(1 << bit_ix);
+        self.insert_hash(self.hash_line(buf));
+    }
+
+    /// Count an occurrence of the line hashed to `hash`, see `insert`.
+    ///
+    /// Panics if `hash` was computed for a sketch using a different hash function or key.
+    #[inline]
+    pub fn insert_hash(&mut self, hash: LineHash) {
+        if self.params.counter_bits == 2 {
+            for (word_ix, bit_ix) in self.as_sketch_ref().hash_probe_iter(hash) {
+                let word = &mut self.words[word_ix];
+                *word |= (*word & 1 << bit_ix).wrapping_add(1 << bit_ix);
+            }
+        } else if self.params.counting {
+            for (word_ix, bit_ix) in self.as_sketch_ref().hash_probe_iter(hash) {
+                if self.counter(word_ix, bit_ix) < self.max_count() {
+                    self.words[word_ix] += 1 << bit_ix;
+                }
+            }
+        } else {
+            let probes = self.as_sketch_ref().hash_probe_iter(hash);
+            let min = probes
+                .clone()
+                .map(|(word_ix, bit_ix)| self.counter(word_ix, bit_ix))
+                .min()
+                .unwrap_or(0);
+
+            if min == self.max_count() {
+                return;
+            }
+
+            for (word_ix, bit_ix) in probes {
+                if self.counter(word_ix, bit_ix) == min {
+                    self.words[word_ix] += 1 << bit_ix;
+                }
+            }
+        }
+    }
+
+    /// Remove an occurrence of `buf` from a counting sketch, see `SketchParams::counting`.
+    ///
+    /// Nothing is removed if `buf` is certainly not in the sketch, in which case this returns
+    /// false. A line that was never inserted can still seem to be in the sketch, in which case
+    /// removing it decrements counters shared with other lines, which then undercount. Queries
+    /// that are otherwise exact or never miss a line can then be wrong. Saturated counters are
+    /// sticky: they are never decremented, as the count they stand for is unknown.
+    ///
+    /// Panics if the sketch is not counting, or if it is keyed and its key has not been set.
+    pub fn remove(&mut self, buf: &[u8]) -> bool {
+        self.remove_hash(self.hash_line(buf))
+    }
+
+    /// Remove an occurrence of a line hashed ahead of time, see `remove` and `hash_line`.
+    pub fn remove_hash(&mut self, hash: LineHash) -> bool {
+        assert!(
+            self.params.counting,
+            "Lines can only be removed from counting sketches"
+        );
+
+        if !self.as_sketch_ref().hash_has_count_at_least(hash, 1) {
+            return false;
+        }
+
+        for (word_ix, bit_ix) in self.as_sketch_ref().hash_probe_iter(hash) {
+            let count = self.counter(word_ix, bit_ix);
+            if count > 0 && count < self.max_count() {
+                self.words[word_ix] -= 1 << bit_ix;
+            }
         }
+        true
+    }
+
+    /// See `DuplicatesSketchRef::line_hasher`.
+    pub fn line_hasher(&self) -> LineHasher {
+        self.as_sketch_ref().line_hasher()
+    }
+
+    /// See `DuplicatesSketchRef::hash_line`.
+    #[inline]
+    pub fn hash_line(&self, buf: &[u8]) -> LineHash {
+        self.as_sketch_ref().hash_line(buf)
     }
 
     #[inline]
     pub fn has_duplicate(&self, buf: &[u8]) -> bool {
-        self.probe_iter(buf)
-            .all(|(word_ix, bit_ix)| self.words[word_ix] >> bit_ix & 0b11 > 1)
+        self.as_sketch_ref().has_duplicate(buf)
     }
 
+    /// See `DuplicatesSketchRef::contains`.
     #[inline]
-    fn probe_iter(&self, buf: &[u8]) -> impl Iterator<Item = (usize, u32)> {
-        let mut hasher = MetroHash128::new();
-        hasher.write(buf);
-        let (hash_a, hash_b) = hasher.finish128();
+    pub fn contains(&self, buf: &[u8]) -> bool {
+        self.as_sketch_ref().contains(buf)
+    }
 
-        let mut hash = hash_a;
-        let len = self.words.len();
-        (0..self.probes).map(move |i| {
-            hash = hash.wrapping_add((i as u64).wrapping_mul(hash_b));
+    /// See `DuplicatesSketchRef::is_certainly_unique`.
+    #[inline]
+    pub fn is_certainly_unique(&self, buf: &[u8]) -> bool {
+        self.as_sketch_ref().is_certainly_unique(buf)
+    }
 
-            (
-                (hash >> (WORD_LEN_BITS - 1)) as usize & (len - 1),
-                (hash & (WORD_BITS / 2 - 1) as u64) as u32 * 2,
-            )
-        })
+    /// See `DuplicatesSketchRef::has_count_at_least`.
+    #[inline]
+    pub fn has_count_at_least(&self, buf: &[u8], k: u32) -> bool {
+        self.as_sketch_ref().has_count_at_least(buf, k)
     }
 
-    pub fn serialize(&self, mut file: impl Write) -> io::Result<()> {
-        file.write_u32::<LittleEndian>(self.probes)?;
-        file.write_u64::<LittleEndian>(self.words.len() as u64)?;
+    /// See `DuplicatesSketchRef::has_count_at_most`.
+    #[inline]
+    pub fn has_count_at_most(&self, buf: &[u8], k: u32) -> bool {
+        self.as_sketch_ref().has_count_at_most(buf, k)
+    }
+
+    /// See `DuplicatesSketchRef::estimate_count`.
+    #[inline]
+    pub fn estimate_count(&self, buf: &[u8]) -> u32 {
+        self.as_sketch_ref().estimate_count(buf)
+    }
+}
 
-        for &word in &self.words {
-            file.write_u32::<LittleEndian>(word)?;
+/// Counters of a sketch, either held by a sketch or borrowed from a serialized one
+#[derive(Debug, Clone, Copy)]
+enum Words<'a> {
+    Native(&'a [Word]),
+    Atomic(&'a [AtomicU32]),
+    /// Little-endian words, as written by `serialize`
+    LittleEndian(&'a [u8]),
+}
+
+impl Words<'_> {
+    fn len(self) -> usize {
+        match self {
+            Words::Native(words) => words.len(),
+            Words::Atomic(words) => words.len(),
+            Words::LittleEndian(bytes) => bytes.len() / size_of::<Word>(),
         }
+    }
 
+    #[inline]
+    fn get(self, ix: usize) -> Word {
+        match self {
+            Words::Native(words) => words[ix],
+            Words::Atomic(words) => words[ix].load(Ordering::Relaxed),
+            Words::LittleEndian(bytes) => LittleEndian::read_u32(&bytes[ix * size_of::<Word>()..]),
+        }
+    }
+}
+
+/// A read-only sketch borrowing its counters.
+///
+/// This can query a serialized sketch in place, such as a memory mapped file, without reading it
+/// into memory first. Use `DuplicatesSketch::as_sketch_ref` to borrow an owned sketch.
+#[derive(Debug, Clone, Copy)]
+pub struct DuplicatesSketchRef<'a> {
+    params: SketchParams,
+    key: Option<HashKey>,
+    key_fingerprint: Option<u64>,
+    words: Words<'a>,
+}
+
+impl<'a> DuplicatesSketchRef<'a> {
+    /// Fingerprint of the key the sketch was built with, if any.
+    pub fn key_fingerprint(&self) -> Option<u64> {
+        self.key_fingerprint
+    }
+
+    /// Set the key of a keyed sketch, see `DuplicatesSketch::set_key`.
+    pub fn set_key(&mut self, key: HashKey) -> Result<(), WrongKey> {
+        if self.key_fingerprint != Some(key.fingerprint()) {
+            return Err(WrongKey);
+        }
+
+        self.key = Some(key);
         Ok(())
     }
 
-    pub fn deserialize(mut file: impl Read) -> io::Result<Option<DuplicatesSketch>> {
-        let probes = match file.read_u32::<LittleEndian>() {
-            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
-            probes => probes?,
+    pub fn params(&self) -> SketchParams {
+        self.params
+    }
+
+    pub fn counter_bits(&self) -> u32 {
+        self.params.counter_bits
+    }
+
+    /// See `DuplicatesSketch::max_count`.
+    pub fn max_count(&self) -> u32 {
+        self.params.max_count()
+    }
+
+    /// Copy the sketch into an owned `DuplicatesSketch`.
+    pub fn to_sketch(&self) -> DuplicatesSketch {
+        DuplicatesSketch {
+            params: self.params,
+            key: self.key,
+            key_fingerprint: self.key_fingerprint,
+            words: (0..self.words.len()).map(|ix| self.words.get(ix)).collect(),
+        }
+    }
+
+    #[inline]
+    fn counter(&self, word_ix: usize, bit_ix: u32) -> u32 {
+        self.params.counter(self.words.get(word_ix), bit_ix)
+    }
+
+    /// Check whether `buf` has probably been inserted at least twice.
+    ///
+    /// Like all queries of a line, this panics if the sketch is keyed and its key has not been
+    /// set, see `set_key`.
+    #[inline]
+    pub fn has_duplicate(&self, buf: &[u8]) -> bool {
+        self.has_count_at_least(buf, 2)
+    }
+
+    /// Check whether `buf` has probably been inserted, as in a Bloom filter.
+    ///
+    /// There are no false negatives, unless counters undercount, see `is_certainly_unique`.
+    #[inline]
+    pub fn contains(&self, buf: &[u8]) -> bool {
+        self.has_count_at_least(buf, 1)
+    }
+
+    /// Check whether `buf` has been inserted at most once.
+    ///
+    /// Unlike `has_duplicate`, this is exact: counters never undercount, so a probed counter below
+    /// 2 proves `buf` was not inserted twice. Lines that were never inserted are also unique.
+    /// Removing lines that were never inserted, or subtracting other sketches, can make counters
+    /// undercount, after which this is no longer exact, see `DuplicatesSketch::remove` and
+    /// `DuplicatesSketch::subtract`.
+    #[inline]
+    pub fn is_certainly_unique(&self, buf: &[u8]) -> bool {
+        !self.has_duplicate(buf)
+    }
+
+    /// Check whether `buf` has probably been inserted at least `k` times.
+    ///
+    /// There are no false negatives, unless counters undercount, see `is_certainly_unique`.
+    /// Counters saturate at `max_count`, so for `k` larger than that, this only tells whether all
+    /// probed counters are saturated.
+    ///
+    /// Panics if the sketch is keyed and its key has not been set, see `set_key`.
+    #[inline]
+    pub fn has_count_at_least(&self, buf: &[u8], k: u32) -> bool {
+        self.hash_has_count_at_least(self.hash_line(buf), k)
+    }
+
+    /// `has_count_at_least` for a line hashed ahead of time, see `hash_line`.
+    #[inline]
+    pub fn hash_has_count_at_least(&self, hash: LineHash, k: u32) -> bool {
+        let k = k.min(self.max_count());
+        self.hash_probe_iter(hash)
+            .all(|(word_ix, bit_ix)| self.counter(word_ix, bit_ix) >= k)
+    }
+
+    /// Check whether `buf` has been inserted at most `k` times.
+    ///
+    /// Like `is_certainly_unique`, this is exact unless counters undercount, but lines occurring
+    /// at most `k` times may be missed when their count is overestimated.
+    ///
+    /// Panics if the sketch is keyed and its key has not been set, see `set_key`.
+    #[inline]
+    pub fn has_count_at_most(&self, buf: &[u8], k: u32) -> bool {
+        self.hash_has_count_at_most(self.hash_line(buf), k)
+    }
+
+    /// `has_count_at_most` for a line hashed ahead of time, see `hash_line`.
+    #[inline]
+    pub fn hash_has_count_at_most(&self, hash: LineHash, k: u32) -> bool {
+        self.hash_probe_iter(hash)
+            .any(|(word_ix, bit_ix)| self.counter(word_ix, bit_ix) <= k)
+    }
+
+    /// Estimate how many times `buf` has been inserted.
+    ///
+    /// This never underestimates, except that counts are saturated at `max_count`, and that
+    /// counters may undercount after removing lines or subtracting sketches, see
+    /// `is_certainly_unique`.
+    ///
+    /// Panics if the sketch is keyed and its key has not been set, see `set_key`.
+    #[inline]
+    pub fn estimate_count(&self, buf: &[u8]) -> u32 {
+        self.hash_estimate_count(self.hash_line(buf))
+    }
+
+    /// `estimate_count` for a line hashed ahead of time, see `hash_line`.
+    #[inline]
+    pub fn hash_estimate_count(&self, hash: LineHash) -> u32 {
+        self.hash_probe_iter(hash)
+            .map(|(word_ix, bit_ix)| self.counter(word_ix, bit_ix))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Hasher for lines inserted into or queried from this sketch.
+    pub fn line_hasher(&self) -> LineHasher {
+        LineHasher {
+            hash: self.params.hash,
+            key: self.key,
+            key_fingerprint: self.key_fingerprint,
+        }
+    }
+
+    /// Hash `buf` for this sketch, and any other sketch using the same hash function and key.
+    ///
+    /// Panics if the sketch is keyed and its key has not been set, see `set_key`.
+    #[inline]
+    pub fn hash_line(&self, buf: &[u8]) -> LineHash {
+        self.line_hasher().hash_line(buf)
+    }
+
+    #[inline]
+    fn probe_iter(&self, buf: &[u8]) -> impl Iterator<Item = (usize, u32)> + Clone {
+        self.hash_probe_iter(self.hash_line(buf))
+    }
+
+    #[inline]
+    fn hash_probe_iter(&self, hash: LineHash) -> impl Iterator<Item = (usize, u32)> + Clone {
+        assert!(
+            hash.hash == self.params.hash && hash.key_fingerprint == self.key_fingerprint,
+            "Line was hashed for a sketch with a different hash function or key"
+        );
+        let (hash_a, hash_b) = hash.halves;
+
+        // Blocked sketches pick a block with the first hash, and probe within it using the second
+        let len = self.words.len();
+        let indexing = self.params.indexing;
+        let (mut hash, step, base, mask) = match self.params.layout {
+            Layout::Flat => (hash_a, hash_b, 0, len - 1),
+            Layout::Blocked => {
+                let blocks = len / BLOCK_WORDS;
+                let block = match indexing {
+                    Indexing::Mask => hash_a as usize & (blocks - 1),
+                    Indexing::FastRange => fastrange(hash_a, blocks),
+                };
+                (
+                    hash_b,
+                    hash_b.rotate_left(32),
+                    block * BLOCK_WORDS,
+                    BLOCK_WORDS - 1,
+                )
+            }
         };
 
-        let mut words = vec![0; file.read_u64::<LittleEndian>()? as usize];
-        file.read_u32_into::<LittleEndian>(&mut words)?;
+        // The slot within a word comes from the low bits of the hash, so flat sketches using a
+        // range reduction take the word from the high bits
+        let fastrange_words = self.params.layout == Layout::Flat && indexing == Indexing::FastRange;
+        let counter_bits = self.params.counter_bits;
+        let slot_bits = (WORD_BITS / counter_bits).trailing_zeros();
+        (0..self.params.probes).map(move |i| {
+            hash = hash.wrapping_add((i as u64).wrapping_mul(step));
 
-        Ok(Some(DuplicatesSketch { probes, words }))
+            let word_ix = if fastrange_words {
+                fastrange(hash, len)
+            } else {
+                base + ((hash >> slot_bits) as usize & mask)
+            };
+            (
+                word_ix,
+                (hash & ((1 << slot_bits) - 1)) as u32 * counter_bits,
+            )
+        })
     }
 }
 
@@ -106,7 +784,10 @@ impl DuplicatesSketch {
 mod tests {
     use super::*;
     use proptest::{collection::vec, prelude::*};
-    use std::{collections::HashMap, io::Cursor};
+    use std::{
+        collections::{HashMap, HashSet},
+        io::Cursor,
+    };
 
     const STRING: &[u8] = b"asdf";
 
@@ -144,6 +825,348 @@ mod tests {
         assert!(sketch.has_duplicate(STRING));
     }
 
+    #[test]
+    fn blocked() {
+        let params = SketchParams {
+            probes: 16,
+            layout: Layout::Blocked,
+            ..Default::default()
+        };
+        for &indexing in &[Indexing::Mask, Indexing::FastRange] {
+            let params = SketchParams { indexing, ..params };
+            let mut sketch = DuplicatesSketch::with_params(params, 4096);
+            sketch.insert(STRING);
+            sketch.insert(STRING);
+            assert!(sketch.has_duplicate(STRING));
+
+            let blocks: HashSet<_> = sketch
+                .as_sketch_ref()
+                .probe_iter(STRING)
+                .map(|(word_ix, _)| word_ix / BLOCK_WORDS)
+                .collect();
+            assert_eq!(blocks.len(), 1);
+        }
+    }
+
+    #[test]
+    fn exact_size() {
+        for &layout in &[Layout::Flat, Layout::Blocked] {
+            let params = SketchParams {
+                probes: 1,
+                layout,
+                indexing: Indexing::FastRange,
+                ..Default::default()
+            };
+            let sketch = DuplicatesSketch::with_params(params, 12 << 10);
+            assert_eq!(sketch.words.len() * size_of::<Word>(), 12 << 10);
+
+            let mask = SketchParams {
+                indexing: Indexing::Mask,
+                ..params
+            };
+            let sketch = DuplicatesSketch::with_params(mask, 12 << 10);
+            assert_eq!(sketch.words.len() * size_of::<Word>(), 16 << 10);
+        }
+    }
+
+    #[test]
+    fn can_fold() {
+        let sketch = DuplicatesSketch::new(1, 4096);
+        assert!(sketch.can_fold_to(4096));
+        assert!(sketch.can_fold_to(1024));
+        assert!(sketch.can_fold_to(4));
+        assert!(!sketch.can_fold_to(3072));
+        assert!(!sketch.can_fold_to(8192));
+        assert!(!sketch.can_fold_to(0));
+        assert_eq!(sketch.max_fold_size(3000), Some(2048));
+        assert_eq!(sketch.max_fold_size(4), Some(4));
+        assert_eq!(sketch.max_fold_size(3), None);
+
+        let sketch = DuplicatesSketch {
+            words: vec![0; 12],
+            ..DuplicatesSketch::new(1, 4)
+        };
+        assert!(!sketch.can_fold_to(24));
+        assert!(!sketch.can_fold_to(16));
+
+        let params = SketchParams {
+            probes: 1,
+            layout: Layout::Blocked,
+            indexing: Indexing::FastRange,
+            ..Default::default()
+        };
+        let sketch = DuplicatesSketch::with_params(params, 12 * 64);
+        assert!(sketch.can_fold_to(4 * 64));
+        assert!(sketch.can_fold_to(3 * 64));
+        assert!(!sketch.can_fold_to(5 * 64));
+        assert!(!sketch.can_fold_to(32));
+        assert_eq!(sketch.max_fold_size(5 * 64), Some(4 * 64));
+        assert_eq!(sketch.max_fold_size(3 * 64 - 1), Some(2 * 64));
+        assert_eq!(sketch.max_fold_size(32), None);
+    }
+
+    #[test]
+    fn presence() {
+        let mut sketch = DuplicatesSketch::with_counter_bits(4, 4, 1024);
+        for source in [&[b"a", b"a", b"b"][..], &[b"b", b"c", b"c"], &[b"b"]] {
+            let mut sub_sketch = DuplicatesSketch::with_counter_bits(4, 4, 1024);
+            source.iter().for_each(|buf| sub_sketch.insert(*buf));
+            sub_sketch.collapse_to_presence();
+            sketch.merge(&sub_sketch);
+        }
+
+        assert_eq!(sketch.estimate_count(b"a"), 1);
+        assert_eq!(sketch.estimate_count(b"b"), 3);
+        assert_eq!(sketch.estimate_count(b"c"), 1);
+        assert_eq!(sketch.estimate_count(b"d"), 0);
+    }
+
+    #[test]
+    fn intersect_subtract() {
+        let sketch = |bufs: &[&[u8]]| {
+            let mut sketch = DuplicatesSketch::with_counter_bits(4, 8, 1024);
+            bufs.iter().for_each(|buf| sketch.insert(buf));
+            sketch
+        };
+        let mut a = sketch(&[b"a", b"a", b"a", b"b"]);
+        let b = sketch(&[b"a", b"c", b"c"]);
+
+        let mut intersection = sketch(&[b"a", b"a", b"a", b"b"]);
+        intersection.intersect(&b);
+        assert_eq!(intersection.estimate_count(b"a"), 1);
+        assert_eq!(intersection.estimate_count(b"b"), 0);
+        assert_eq!(intersection.estimate_count(b"c"), 0);
+
+        a.subtract(&b);
+        assert_eq!(a.estimate_count(b"a"), 2);
+        assert_eq!(a.estimate_count(b"b"), 1);
+        assert_eq!(a.estimate_count(b"c"), 0);
+    }
+
+    #[test]
+    fn all_words_reachable() {
+        for &(layout, size) in &[
+            (Layout::Flat, 4),
+            (Layout::Flat, 7 * 4),
+            (Layout::Flat, 100 * 4),
+            (Layout::Blocked, 3 * 64),
+            (Layout::Blocked, 5 * 64),
+        ] {
+            for &counter_bits in COUNTER_BITS {
+                let params = SketchParams {
+                    probes: 1,
+                    counter_bits,
+                    layout,
+                    indexing: Indexing::FastRange,
+                    ..Default::default()
+                };
+                let mut sketch = DuplicatesSketch::with_params(params, size);
+                assert_eq!(sketch.words.len() * size_of::<Word>(), size);
+
+                // Every counter, not just every word, is hit by some line
+                let slots = WORD_BITS / counter_bits;
+                for i in 0..sketch.words.len() as u32 * slots * 20 {
+                    sketch.insert(&i.to_le_bytes());
+                }
+                for &word in &sketch.words {
+                    for slot in 0..slots {
+                        let counter = word >> (slot * counter_bits) & ((1 << counter_bits) - 1);
+                        assert_ne!(counter, 0, "{:?} {} {}", layout, size, counter_bits);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn line_hash() {
+        let mut small = DuplicatesSketch::new(4, 1024);
+        let mut large = DuplicatesSketch::new(4, 4096);
+        let hash = small.hash_line(STRING);
+        small.insert_hash(hash);
+        small.insert_hash(hash);
+        large.insert_hash(hash);
+
+        assert!(small.has_duplicate(STRING));
+        assert_eq!(large.estimate_count(STRING), 1);
+
+        assert!(small.as_sketch_ref().hash_has_count_at_least(hash, 2));
+        assert!(large.as_sketch_ref().hash_has_count_at_most(hash, 1));
+        assert_eq!(large.as_sketch_ref().hash_estimate_count(hash), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn line_hash_mismatch() {
+        let params = SketchParams {
+            hash: HashFunction::Xxh3,
+            ..DuplicatesSketch::new(4, 1024).params()
+        };
+        let hash = DuplicatesSketch::new(4, 1024).hash_line(STRING);
+        DuplicatesSketch::with_params(params, 1024).insert_hash(hash);
+    }
+
+    #[test]
+    fn keyed() {
+        let params = DuplicatesSketch::new(16, 4096).params();
+        let key = HashKey::new([1; 16]);
+        let mut sketch = DuplicatesSketch::with_key(params, key, 4096);
+        sketch.insert(STRING);
+        sketch.insert(STRING);
+        assert!(sketch.has_duplicate(STRING));
+
+        let mut buf = Vec::new();
+        sketch.serialize(Cursor::new(&mut buf)).unwrap();
+        let mut deserialized = DuplicatesSketch::deserialize(Cursor::new(buf))
+            .unwrap()
+            .unwrap();
+        assert_eq!(deserialized.key_fingerprint(), Some(key.fingerprint()));
+        assert!(deserialized.set_key(HashKey::new([2; 16])).is_err());
+        deserialized.set_key(key).unwrap();
+        assert_eq!(deserialized, sketch);
+
+        let unkeyed = DuplicatesSketch::new(16, 4096);
+        assert!(!unkeyed.is_compatible(&sketch));
+        assert!(DuplicatesSketch::new(16, 4096).set_key(key).is_err());
+    }
+
+    #[test]
+    fn unique() {
+        let mut sketch = DuplicatesSketch::new(16, 4096);
+        assert!(sketch.is_certainly_unique(STRING));
+        sketch.insert(STRING);
+        assert!(sketch.is_certainly_unique(STRING));
+        sketch.insert(STRING);
+        assert!(!sketch.is_certainly_unique(STRING));
+    }
+
+    #[test]
+    fn contains() {
+        let mut sketch = DuplicatesSketch::new(16, 4096);
+        assert!(!sketch.contains(STRING));
+        sketch.insert(STRING);
+        assert!(sketch.contains(STRING));
+        sketch.insert(STRING);
+        assert!(sketch.contains(STRING));
+    }
+
+    #[test]
+    fn count_at_least() {
+        let mut sketch = DuplicatesSketch::with_counter_bits(16, 4, 4096);
+        for _ in 0..5 {
+            sketch.insert(STRING);
+        }
+        assert!(sketch.has_duplicate(STRING));
+        assert!(sketch.has_count_at_least(STRING, 5));
+        assert!(!sketch.has_count_at_least(STRING, 6));
+    }
+
+    #[test]
+    fn count_at_most() {
+        let mut sketch = DuplicatesSketch::with_counter_bits(16, 4, 4096);
+        for _ in 0..3 {
+            sketch.insert(STRING);
+        }
+        assert!(!sketch.has_count_at_most(STRING, 2));
+        assert!(sketch.has_count_at_most(STRING, 3));
+        assert!(sketch.has_count_at_most(STRING, 4));
+    }
+
+    #[test]
+    fn estimate() {
+        let mut sketch = DuplicatesSketch::with_counter_bits(16, 8, 4096);
+        assert_eq!(sketch.estimate_count(STRING), 0);
+        for _ in 0..7 {
+            sketch.insert(STRING);
+        }
+        assert_eq!(sketch.estimate_count(STRING), 7);
+
+        let mut sketch = DuplicatesSketch::new(16, 4096);
+        for _ in 0..7 {
+            sketch.insert(STRING);
+        }
+        assert_eq!(sketch.estimate_count(STRING), 2);
+    }
+
+    #[test]
+    fn remove() {
+        let params = SketchParams {
+            counting: true,
+            ..DuplicatesSketch::with_counter_bits(4, 4, 1024).params()
+        };
+        let mut sketch = DuplicatesSketch::with_params(params, 1024);
+        for _ in 0..3 {
+            sketch.insert(STRING);
+        }
+        sketch.insert(b"other");
+
+        assert!(sketch.remove(STRING));
+        assert_eq!(sketch.estimate_count(STRING), 2);
+        assert_eq!(sketch.estimate_count(b"other"), 1);
+
+        // Lines that are certainly absent are not removed
+        let words = sketch.words.clone();
+        assert!(!sketch.remove(b"absent"));
+        assert_eq!(sketch.words, words);
+
+        // Saturated counters are sticky
+        for _ in 0..20 {
+            sketch.insert(STRING);
+        }
+        assert!(sketch.remove(STRING));
+        assert_eq!(sketch.estimate_count(STRING), sketch.max_count());
+    }
+
+    #[test]
+    fn subtract_undercounts() {
+        let mut sketch = DuplicatesSketch::with_counter_bits(1, 4, 4);
+        sketch.insert(STRING);
+        sketch.insert(STRING);
+
+        // Lines of `other` that share a counter with `STRING` are subtracted from it
+        let mut other = DuplicatesSketch::with_counter_bits(1, 4, 4);
+        for i in 0..20u32 {
+            other.insert(&i.to_le_bytes());
+        }
+        sketch.subtract(&other);
+        assert!(sketch.estimate_count(STRING) < 2);
+        assert!(sketch.is_certainly_unique(STRING));
+    }
+
+    #[test]
+    fn remove_false_positive() {
+        let params = SketchParams {
+            counting: true,
+            ..DuplicatesSketch::with_counter_bits(1, 4, 4).params()
+        };
+        let mut sketch = DuplicatesSketch::with_params(params, 4);
+        sketch.insert(STRING);
+        sketch.insert(STRING);
+
+        // Lines that were never inserted, but share a counter with `STRING`, are removed from it
+        for i in 0..20u32 {
+            sketch.remove(&i.to_le_bytes());
+        }
+        assert!(sketch.estimate_count(STRING) < 2);
+        assert!(sketch.is_certainly_unique(STRING));
+    }
+
+    #[test]
+    #[should_panic]
+    fn remove_not_counting() {
+        DuplicatesSketch::with_counter_bits(4, 4, 1024).remove(STRING);
+    }
+
+    #[test]
+    fn saturate() {
+        let mut sketch = DuplicatesSketch::with_counter_bits(16, 4, 4096);
+        for _ in 0..20 {
+            sketch.insert(STRING);
+        }
+        assert!(sketch.has_count_at_least(STRING, 15));
+        assert!(sketch.has_count_at_least(STRING, 20));
+    }
+
     fn check(sketch: &DuplicatesSketch, bufs: &Vec<Vec<u8>>) -> Result<(), TestCaseError> {
         let mut counts = HashMap::new();
         for buf in bufs {
@@ -151,15 +1174,63 @@ mod tests {
         }
 
         for buf in bufs {
-            prop_assert!(*counts.get(buf).unwrap() < 2 || sketch.has_duplicate(&buf));
+            prop_assert!(*counts.get(buf).unwrap() < 2 || sketch.has_duplicate(buf));
+            prop_assert!(*counts.get(buf).unwrap() < 2 || !sketch.is_certainly_unique(buf));
         }
 
         Ok(())
     }
 
+    pub(crate) fn check_counts(
+        sketch: &DuplicatesSketch,
+        bufs: &[Vec<u8>],
+    ) -> Result<(), TestCaseError> {
+        let mut counts = HashMap::new();
+        for buf in bufs {
+            *counts.entry(buf.clone()).or_insert(0) += 1;
+        }
+
+        for (buf, &count) in &counts {
+            prop_assert!(sketch.has_count_at_least(buf, count));
+            prop_assert!(sketch.estimate_count(buf) >= count.min(sketch.max_count()));
+            prop_assert!(
+                count <= 1
+                    || count > sketch.max_count()
+                    || !sketch.has_count_at_most(buf, count - 1)
+            );
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn sketch_params() -> impl Strategy<Value = SketchParams> {
+        (
+            1..8u32,
+            proptest::sample::select(COUNTER_BITS),
+            prop_oneof![Just(Layout::Flat), Just(Layout::Blocked)],
+            prop_oneof![
+                Just(HashFunction::Metro),
+                Just(HashFunction::Xxh3),
+                Just(HashFunction::Sip)
+            ],
+            prop_oneof![Just(Indexing::Mask), Just(Indexing::FastRange)],
+            any::<bool>(),
+        )
+            .prop_map(|(probes, counter_bits, layout, hash, indexing, counting)| {
+                SketchParams {
+                    probes,
+                    counter_bits,
+                    layout,
+                    hash,
+                    indexing,
+                    counting: counting && counter_bits > 2,
+                }
+            })
+    }
+
     prop_compose! {
         /// Generate vecs of vecs of bytes, but make it likely for there to be duplicated buffers
-        fn duplicated_bufs()
+        pub(crate) fn duplicated_bufs()
                           (len in 1..100usize)
                           (bufs in vec(vec(0..=255u8, 0..100usize), len),
                            indices in vec(0..len, 0..100usize))
@@ -199,6 +1270,13 @@ mod tests {
             check(&sketch, &bufs)?;
         }
 
+        #[test]
+        fn insert_counts(bufs in duplicated_bufs(), params in sketch_params()) {
+            let mut sketch = DuplicatesSketch::with_params(params, 1024);
+            bufs.iter().for_each(|buf| sketch.insert(buf));
+            check_counts(&sketch, &bufs)?;
+        }
+
         #[test]
         fn merge(bufs in duplicated_multibufs()) {
             let mut sketch = DuplicatesSketch::new(4, 1024);
@@ -228,5 +1306,78 @@ mod tests {
 
             prop_assert_eq!(sketch_a, sketch_b);
         }
+
+        #[test]
+        fn merge_counts(bufs in duplicated_multibufs(), params in sketch_params()) {
+            let mut sketch = DuplicatesSketch::with_params(params, 1024);
+
+            for bufs in &bufs {
+                let mut sub_sketch = DuplicatesSketch::with_params(params, 1024);
+                bufs.iter().for_each(|buf| sub_sketch.insert(buf));
+                sketch.merge(&sub_sketch);
+            }
+
+            let merged_bufs: Vec<_> = bufs
+                .iter()
+                .flat_map(|bufs| bufs.iter().cloned())
+                .collect();
+
+            check_counts(&sketch, &merged_bufs)?;
+        }
+
+        #[test]
+        fn fold(bufs in duplicated_bufs(), params in sketch_params()) {
+            let size = match params.indexing {
+                Indexing::Mask => 16384,
+                Indexing::FastRange => 12288,
+            };
+            let mut sketch = DuplicatesSketch::with_params(params, size);
+            bufs.iter().for_each(|buf| sketch.insert(buf));
+            sketch.fold_to(1024);
+            prop_assert_eq!(sketch.words.len() * size_of::<Word>(), 1024);
+            check_counts(&sketch, &bufs)?;
+
+            // Without conservative update, folding gives exactly the smaller sketch
+            if params.counter_bits == 2 {
+                let mut small = DuplicatesSketch::with_params(params, 1024);
+                bufs.iter().for_each(|buf| small.insert(buf));
+                prop_assert_eq!(sketch, small);
+            }
+        }
+
+        #[test]
+        fn remove_counts(
+            bufs in duplicated_bufs(),
+            params in sketch_params().prop_filter("counting", |params| params.counter_bits > 2),
+        ) {
+            let params = SketchParams { counting: true, ..params };
+            let mut sketch = DuplicatesSketch::with_params(params, 1024);
+            bufs.iter().for_each(|buf| sketch.insert(buf));
+
+            let (removed, kept) = bufs.split_at(bufs.len() / 2);
+            for buf in removed {
+                prop_assert!(sketch.remove(buf));
+            }
+
+            check_counts(&sketch, kept)?;
+        }
+
+        #[test]
+        fn serialize_params(bufs in duplicated_bufs(), params in sketch_params()) {
+            let mut sketch_a = DuplicatesSketch::with_params(params, 1024);
+            bufs.iter().for_each(|buf| sketch_a.insert(buf));
+
+            let mut buf = Vec::new();
+            sketch_a.serialize(Cursor::new(&mut buf))?;
+            let sketch_b = DuplicatesSketch::deserialize(Cursor::new(buf))?.unwrap();
+
+            prop_assert_eq!(&sketch_a, &sketch_b);
+
+            let mut buf = Vec::new();
+            sketch_a.serialize_compressed(Cursor::new(&mut buf))?;
+            let sketch_c = DuplicatesSketch::deserialize(Cursor::new(buf))?.unwrap();
+
+            prop_assert_eq!(sketch_a, sketch_c);
+        }
     }
 }
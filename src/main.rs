@@ -1,18 +1,32 @@
+mod predicate;
+
 use anyhow::{anyhow, Error};
 use human_size::{Byte, Size};
-use sketch_duplicates::DuplicatesSketch;
+use memmap2::Mmap;
+use predicate::Predicate;
+use sketch_duplicates::{
+    plan_for_rate, plan_for_size, AtomicDuplicatesSketch, DuplicatesSketch, DuplicatesSketchRef,
+    HashFunction, HashKey, Indexing, Layout, LineHash, LineHasher, SketchParams, WindowedSketch,
+    COUNTER_BITS, MAX_PROBES,
+};
 use std::{
-    fs::File,
-    io::{stdin, stdout, BufRead, BufReader, BufWriter, Read, Write},
-    path::PathBuf,
+    collections::VecDeque,
+    fs::{read_to_string, File},
+    io::{self, stdin, stdout, BufRead, BufReader, BufWriter, Read, Write},
+    mem,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, Sender},
+        Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
-#[structopt(
-    name = "dubs-sketch",
-    about = "Find duplicate lines probabilistically"
-)]
+#[structopt(name = "dubs-sketch", about = "Find duplicate lines probabilistically")]
 enum Opt {
     #[structopt(about = "Build a sketch from lines in standard input")]
     Build {
@@ -28,10 +42,82 @@ enum Opt {
             short,
             long,
             default_value = "8MiB",
-            about = "Minimum size of the sketch. Actual size will be nearest power of two larger than this."
+            about = "Size of the sketch. With mask indexing, this is rounded up to a power of two."
         )]
         size: Size,
 
+        #[structopt(
+            long,
+            default_value = "fastrange",
+            parse(try_from_str = parse_indexing),
+            about = "How hashes are mapped to counters, either \"fastrange\" or \"mask\". Mask indexing needs sizes rounded up to a power of two, and is what sketches written by earlier versions use."
+        )]
+        indexing: Indexing,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "2",
+            about = "Bits per counter in the sketch. Must be 2, 4, 8 or 16. Wider counters can count more occurrences, but fit fewer counters in the same size."
+        )]
+        counter_bits: u32,
+
+        #[structopt(
+            long,
+            about = "Increment all counters probed for a line, so that lines can be removed again with --subtract-from. Needs counters wider than 2 bits."
+        )]
+        counting: bool,
+
+        #[structopt(
+            long,
+            about = "Instead of building a new sketch, remove the lines in standard input from this sketch, which must have been built with --counting. Saturated counters are never decremented. Removing lines that were never inserted can make counts of other lines too small. Other options for the sketch are taken from it."
+        )]
+        subtract_from: Option<PathBuf>,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "flat",
+            parse(try_from_str = parse_layout),
+            about = "Layout of the sketch, either \"flat\" or \"blocked\". Blocked sketches keep all probes for a line within one cache line, which is faster for large sketches, but slightly less precise."
+        )]
+        layout: Layout,
+
+        #[structopt(
+            long,
+            default_value = "metro",
+            parse(try_from_str = parse_hash),
+            about = "Hash function of the sketch, one of \"metro\", \"xxh3\" or \"sip\". Only sketches using the same hash function can be combined."
+        )]
+        hash: HashFunction,
+
+        #[structopt(
+            short,
+            long,
+            about = "File containing a key to hash lines with, as generated by keygen. Keyed sketches resist crafted inputs, and cannot be queried without the key."
+        )]
+        key_file: Option<PathBuf>,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "1",
+            about = "Number of threads hashing and inserting lines. The sketch is the same for any number of threads."
+        )]
+        threads: usize,
+
+        #[structopt(
+            long,
+            about = "Print statistics of the sketch to standard error when done, as by the stats command."
+        )]
+        stats: bool,
+
+        #[structopt(
+            long,
+            about = "Write runs of zero words compactly. This makes sketches of few lines much smaller. Compressed sketches are read into memory by filter, instead of being queried in place."
+        )]
+        compress: bool,
+
         #[structopt(
             short = "0",
             long,
@@ -40,40 +126,1045 @@ enum Opt {
         zero_terminated: bool,
     },
     #[structopt(about = "Combine multiple sketches into one.")]
-    Combine,
+    Combine {
+        #[structopt(
+            long,
+            about = "Refuse to read sketches larger than this, instead of trying to allocate memory for them."
+        )]
+        max_sketch_size: Option<Size>,
+
+        #[structopt(
+            long,
+            default_value = "union",
+            parse(try_from_str = parse_combine_op),
+            about = "How counts are combined, one of \"union\" (adding them up), \"intersect\" (taking the smallest) or \"subtract\" (subtracting the counts of all other sketches from the first). Subtracting can make counts too small, as counts in the subtracted sketches may be too large."
+        )]
+        op: CombineOp,
+
+        #[structopt(
+            long,
+            about = "Count each line at most once per input sketch, so that filter --min-count K outputs lines present in at least K of them. Counting past 2 sketches needs counters wider than 2 bits."
+        )]
+        presence: bool,
+
+        #[structopt(
+            long,
+            about = "Write runs of zero words compactly, see build. Compressed sketches are read into memory by filter, instead of being queried in place."
+        )]
+        compress: bool,
+    },
+    #[structopt(about = "Fold a sketch from standard input to a smaller size.")]
+    Shrink {
+        #[structopt(
+            short,
+            long,
+            about = "Largest size of the folded sketch. The sketch is folded to the largest size it can be that is at most this."
+        )]
+        size: Size,
+
+        #[structopt(
+            long,
+            about = "Refuse to read sketches larger than this, instead of trying to allocate memory for them."
+        )]
+        max_sketch_size: Option<Size>,
+
+        #[structopt(long, about = "Write runs of zero words compactly, see build.")]
+        compress: bool,
+    },
     #[structopt(about = "Remove most lines that do not have duplicates.")]
     Filter {
         #[structopt(
+            required_unless = "sketches",
+            conflicts_with = "sketches",
             about = "Sketch to filter by."
         )]
+        sketch: Option<PathBuf>,
+
+        #[structopt(
+            short = "s",
+            long = "sketch",
+            number_of_values = 1,
+            requires = "predicate",
+            parse(try_from_str = parse_named_sketch),
+            about = "Sketch to filter by, given as NAME=PATH, for use in --where. Can be given several times."
+        )]
+        sketches: Vec<(String, PathBuf)>,
+
+        #[structopt(
+            long = "where",
+            requires = "sketches",
+            conflicts_with_all = &["min-count", "uniques", "exact-count", "max-count", "present-in"],
+            about = "Only output lines matching this predicate over the sketches given with --sketch, such as \"dup(a) && !seen(b)\". dup(NAME) and seen(NAME) test whether a line probably occurs at least twice or at all, count(NAME) compares its estimated count with >=, >, <=, <, == or !=, and tests combine with !, && and ||."
+        )]
+        predicate: Option<String>,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "2",
+            about = "Only output lines that occur at least this many times. Cannot exceed what the counters of the sketch can count."
+        )]
+        min_count: u32,
+
+        #[structopt(
+            short,
+            long,
+            conflicts_with_all = &["min-count", "exact-count", "max-count"],
+            about = "Only output lines that certainly occur at most once. Unlike other filters, this is exact, unless lines that were never inserted were removed from the sketch with build --subtract-from, or the sketch was made by combine --op subtract."
+        )]
+        uniques: bool,
+
+        #[structopt(
+            short,
+            long,
+            conflicts_with_all = &["min-count", "max-count"],
+            about = "Only output lines with an estimated count of exactly this. Counts are never underestimated, so lines occurring fewer times may be output, and lines occurring this many times may be missed."
+        )]
+        exact_count: Option<u32>,
+
+        #[structopt(
+            short = "M",
+            long,
+            conflicts_with = "min-count",
+            about = "Only output lines that certainly occur at most this many times. Counts are never underestimated, so no line occurring more often is output, but some lines occurring this many times may be missed. Removing lines with build --subtract-from, or combine --op subtract, can make counts too small, so that lines occurring more often are output."
+        )]
+        max_count: Option<u32>,
+
+        #[structopt(
+            long,
+            conflicts_with_all = &["min-count", "uniques", "exact-count", "max-count"],
+            about = "Only output lines that were probably inserted into the sketch at all, such as lines of another dataset that the sketch was built from. No such line is missed, unless lines that were never inserted were removed from the sketch with build --subtract-from, or the sketch was made by combine --op subtract."
+        )]
+        present_in: bool,
+
+        #[structopt(
+            short,
+            long,
+            about = "File containing the key the sketch was built with, as generated by keygen."
+        )]
+        key_file: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            about = "Refuse to read sketches larger than this, instead of trying to allocate memory for them."
+        )]
+        max_sketch_size: Option<Size>,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "1",
+            about = "Number of threads checking lines against the sketch."
+        )]
+        threads: usize,
+
+        #[structopt(
+            long,
+            about = "Output lines as soon as they are checked, instead of in input order. Lines are checked in chunks, so only lines from different chunks are reordered."
+        )]
+        unordered: bool,
+
+        #[structopt(
+            short = "0",
+            long,
+            about = "Use NULL bytes as line delimiters instead of newlines."
+        )]
+        zero_terminated: bool,
+    },
+    #[structopt(
+        about = "Output lines of standard input that repeat within a sliding window of recent lines."
+    )]
+    Window {
+        #[structopt(
+            long,
+            required_unless = "seconds",
+            conflicts_with = "seconds",
+            about = "Output lines repeating within this many lines."
+        )]
+        lines: Option<u64>,
+
+        #[structopt(long, about = "Output lines repeating within this many seconds.")]
+        seconds: Option<f64>,
+
+        #[structopt(
+            long,
+            requires = "seconds",
+            about = "Take the time of each line from this whitespace separated field, counting from 1, as Unix seconds. Lines are compared without this field. Lines without a valid time are given the time of the line before. By default, lines are timed as they are read."
+        )]
+        timestamp_field: Option<usize>,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "4",
+            about = "Number of generations the window advances by. Repeats up to a generation further apart than the window may also be output. More generations make the window more exact, but cost more memory and time."
+        )]
+        generations: usize,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "8MiB",
+            about = "Size of the sketch of each generation."
+        )]
+        size: Size,
+
+        #[structopt(
+            short,
+            long,
+            default_value = "2",
+            about = "Number of probes in sketch. Larger values are more precise, but slower"
+        )]
+        probes: u32,
+
+        #[structopt(
+            short = "0",
+            long,
+            about = "Use NULL bytes as line delimiters instead of newlines."
+        )]
+        zero_terminated: bool,
+    },
+    #[structopt(about = "Print the estimated number of occurrences of lines.")]
+    Query {
+        #[structopt(about = "Sketch to query.")]
         sketch: PathBuf,
-    
+
+        #[structopt(
+            about = "Lines to query. If none are given, lines are read from standard input."
+        )]
+        lines: Vec<String>,
+
+        #[structopt(
+            short,
+            long,
+            about = "File containing the key the sketch was built with, as generated by keygen."
+        )]
+        key_file: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            about = "Refuse to read sketches larger than this, instead of trying to allocate memory for them."
+        )]
+        max_sketch_size: Option<Size>,
+
         #[structopt(
             short = "0",
             long,
             about = "Use NULL bytes as line delimiters instead of newlines."
         )]
-        zero_terminated: bool
+        zero_terminated: bool,
+    },
+    #[structopt(about = "Print statistics of a sketch, to tell how full it is.")]
+    Stats {
+        #[structopt(about = "Sketch to print statistics of.")]
+        sketch: PathBuf,
+
+        #[structopt(long, about = "Print statistics as JSON.")]
+        json: bool,
+
+        #[structopt(
+            long,
+            about = "Refuse to read sketches larger than this, instead of trying to allocate memory for them."
+        )]
+        max_sketch_size: Option<Size>,
+    },
+    #[structopt(about = "Generate a random key for building keyed sketches.")]
+    Keygen,
+    #[structopt(
+        about = "Recommend a sketch size and number of probes for the expected number of distinct lines."
+    )]
+    Plan {
+        #[structopt(short, long, about = "Expected number of distinct lines.")]
+        distinct: u64,
+
+        #[structopt(
+            short = "f",
+            long,
+            required_unless = "memory",
+            conflicts_with = "memory",
+            about = "Largest acceptable fraction of lines occurring once to be output by filter. The smallest sketch achieving this is recommended."
+        )]
+        target_fpr: Option<f64>,
+
+        #[structopt(
+            short,
+            long,
+            about = "Size of the sketch. The number of probes giving the fewest false positives in this size is recommended."
+        )]
+        memory: Option<Size>,
     },
 }
 
-fn combine_sketches(mut r: impl Read) -> Result<DuplicatesSketch, Error> {
-    let mut sketch: Option<DuplicatesSketch> = None;
+fn parse_layout(s: &str) -> Result<Layout, Error> {
+    match s {
+        "flat" => Ok(Layout::Flat),
+        "blocked" => Ok(Layout::Blocked),
+        _ => Err(anyhow!("Unknown layout \"{}\"", s)),
+    }
+}
+
+/// How `combine` combines the counts of sketches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CombineOp {
+    Union,
+    Intersect,
+    Subtract,
+}
 
-    while let Some(to_merge) = DuplicatesSketch::deserialize(&mut r)? {
-        match sketch {
-            Some(ref mut sketch) => {
-                if !sketch.is_compatible(&to_merge) {
-                    return Err(anyhow!("Incompatible sketches"));
+fn parse_combine_op(s: &str) -> Result<CombineOp, Error> {
+    match s {
+        "union" => Ok(CombineOp::Union),
+        "intersect" => Ok(CombineOp::Intersect),
+        "subtract" => Ok(CombineOp::Subtract),
+        _ => Err(anyhow!("Unknown operation \"{}\"", s)),
+    }
+}
+
+fn parse_indexing(s: &str) -> Result<Indexing, Error> {
+    match s {
+        "mask" => Ok(Indexing::Mask),
+        "fastrange" => Ok(Indexing::FastRange),
+        _ => Err(anyhow!("Unknown indexing \"{}\"", s)),
+    }
+}
+
+fn parse_hash(s: &str) -> Result<HashFunction, Error> {
+    HashFunction::from_name(s).ok_or_else(|| anyhow!("Unknown hash function \"{}\"", s))
+}
+
+/// Byte range of the whitespace separated `field` of `line`, counting from 1
+fn field_range(line: &[u8], field: usize) -> Option<Range<usize>> {
+    let mut range = 0..0;
+    for _ in 0..field {
+        let start = range.end
+            + line[range.end..]
+                .iter()
+                .position(|byte| !byte.is_ascii_whitespace())?;
+        let end = line[start..]
+            .iter()
+            .position(|byte| byte.is_ascii_whitespace())
+            .map_or(line.len(), |len| start + len);
+        range = start..end;
+    }
+
+    Some(range)
+}
+
+/// Parse `field` as a finite number of Unix seconds
+fn parse_time(field: &[u8]) -> Option<f64> {
+    std::str::from_utf8(field)
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|time: &f64| time.is_finite())
+}
+
+/// Format `bytes` with the largest binary unit it has at least one of
+fn format_size(bytes: usize) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let exp = ((bytes.max(1) as f64).log2() as usize / 10).min(units.len() - 1);
+    if exp == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!(
+            "{:.2} {}",
+            bytes as f64 / (1u64 << (10 * exp)) as f64,
+            units[exp]
+        )
+    }
+}
+
+fn write_stats(output: &mut impl Write, sketch: &DuplicatesSketchRef) -> io::Result<()> {
+    let stats = sketch.stats();
+    let bits = sketch.counter_bits();
+    let size = (stats.counters * u64::from(bits) / 8) as usize;
+    let percent = |counters: u64| 100.0 * counters as f64 / stats.counters as f64;
+
+    writeln!(output, "Size: {} bytes ({})", size, format_size(size))?;
+    writeln!(
+        output,
+        "Counters: {} {}-bit counters, {} probes",
+        stats.counters,
+        bits,
+        sketch.params().probes
+    )?;
+    for (count, &counters) in stats.histogram.iter().enumerate().take(2) {
+        writeln!(
+            output,
+            "Counters at {}: {} ({:.2}%)",
+            count,
+            counters,
+            percent(counters)
+        )?;
+    }
+    let at_least_2 = stats.histogram[2..].iter().sum();
+    writeln!(
+        output,
+        "Counters at 2 or more: {} ({:.2}%)",
+        at_least_2,
+        percent(at_least_2)
+    )?;
+    if sketch.max_count() > 2 {
+        let saturated = stats.histogram[sketch.max_count() as usize];
+        writeln!(
+            output,
+            "Saturated counters: {} ({:.2}%)",
+            saturated,
+            percent(saturated)
+        )?;
+    }
+    writeln!(output, "Fill ratio: {:.4}", stats.fill_ratio)?;
+    if stats.distinct_lines.is_finite() {
+        writeln!(
+            output,
+            "Estimated distinct lines: {:.0}",
+            stats.distinct_lines
+        )?;
+        writeln!(
+            output,
+            "Estimated duplicated lines: {:.0}",
+            stats.duplicated_lines
+        )?;
+    } else {
+        writeln!(
+            output,
+            "Estimated distinct lines: too many to estimate, all counters are in use"
+        )?;
+    }
+    writeln!(
+        output,
+        "Predicted false positive rate: {:.6}",
+        stats.false_positive_rate
+    )
+}
+
+fn write_stats_json(output: &mut impl Write, sketch: &DuplicatesSketchRef) -> io::Result<()> {
+    // Estimates are infinite for full sketches, which JSON has no number for
+    let number = |x: f64| {
+        if x.is_finite() {
+            x.to_string()
+        } else {
+            "null".to_string()
+        }
+    };
+
+    let stats = sketch.stats();
+    let histogram: Vec<_> = stats.histogram.iter().map(u64::to_string).collect();
+    writeln!(
+        output,
+        "{{\"counter_bits\":{},\"probes\":{},\"counters\":{},\"histogram\":[{}],\"fill_ratio\":{},\"distinct_lines\":{},\"duplicated_lines\":{},\"false_positive_rate\":{}}}",
+        sketch.counter_bits(),
+        sketch.params().probes,
+        stats.counters,
+        histogram.join(","),
+        number(stats.fill_ratio),
+        number(stats.distinct_lines),
+        number(stats.duplicated_lines),
+        number(stats.false_positive_rate),
+    )
+}
+
+/// Condition for lines to be output by `filter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Condition {
+    AtLeast(u32),
+    AtMost(u32),
+    Exactly(u32),
+    Unique,
+    Present,
+}
+
+impl Condition {
+    fn matches(&self, sketch: &DuplicatesSketchRef, buf: &[u8]) -> bool {
+        self.matches_hash(sketch, sketch.hash_line(buf))
+    }
+
+    fn matches_hash(&self, sketch: &DuplicatesSketchRef, hash: LineHash) -> bool {
+        match *self {
+            Condition::AtLeast(k) => sketch.hash_has_count_at_least(hash, k),
+            Condition::AtMost(k) => sketch.hash_has_count_at_most(hash, k),
+            Condition::Exactly(k) => sketch.hash_estimate_count(hash) == k,
+            Condition::Unique => !sketch.hash_has_count_at_least(hash, 2),
+            Condition::Present => sketch.hash_has_count_at_least(hash, 1),
+        }
+    }
+
+    /// Check that the counters of `sketch` can count far enough for the condition
+    fn check(&self, sketch: &DuplicatesSketchRef) -> Result<(), Error> {
+        let (bits, max) = (sketch.counter_bits(), sketch.max_count());
+        match *self {
+            Condition::AtLeast(k) if k > max => Err(anyhow!(
+                "Sketch has {}-bit counters, which cannot count more than {} occurrences",
+                bits,
+                max
+            )),
+            Condition::AtMost(k) | Condition::Exactly(k) if k >= max => Err(anyhow!(
+                "Sketch has {}-bit counters, which cannot tell apart counts of {} or more",
+                bits,
+                max
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn parse_named_sketch(s: &str) -> Result<(String, PathBuf), Error> {
+    let (name, path) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Sketch must be given as NAME=PATH"))?;
+    if name.is_empty() || !name.chars().all(predicate::is_name_char) {
+        return Err(anyhow!(
+            "Sketch names must consist of letters, digits and underscores"
+        ));
+    }
+
+    Ok((name.to_owned(), PathBuf::from(path)))
+}
+
+fn read_key(path: &Path) -> Result<HashKey, Error> {
+    HashKey::from_hex(read_to_string(path)?.trim())
+        .ok_or_else(|| anyhow!("Key file must contain 32 hexadecimal digits"))
+}
+
+/// Read the key of a sketch with `fingerprint` from `key_file`, if the sketch is keyed
+fn sketch_key(fingerprint: Option<u64>, key_file: Option<&Path>) -> Result<Option<HashKey>, Error> {
+    match (fingerprint, key_file) {
+        (None, None) => Ok(None),
+        (Some(_), None) => Err(anyhow!(
+            "Sketch was built with a key, which must be given with --key-file"
+        )),
+        (None, Some(_)) => Err(anyhow!("Sketch was built without a key")),
+        (Some(_), Some(key_file)) => read_key(key_file).map(Some),
+    }
+}
+
+/// Read and combine the sketches in `path`, and set their key if they are keyed
+fn load_sketch(
+    path: &Path,
+    key_file: Option<&Path>,
+    max_size: Option<Size>,
+) -> Result<DuplicatesSketch, Error> {
+    let mut sketch = combine_sketches(
+        BufReader::new(File::open(path)?),
+        max_size,
+        CombineOp::Union,
+        false,
+    )?;
+    if let Some(key) = sketch_key(sketch.key_fingerprint(), key_file)? {
+        sketch.set_key(key)?;
+    }
+
+    Ok(sketch)
+}
+
+/// Memory map `path`, if it is a file that can be mapped
+fn map_file(path: &Path) -> Option<Mmap> {
+    let file = File::open(path).ok()?;
+    // Sketches must not be modified while they are mapped, just like while they are read
+    unsafe { Mmap::map(&file) }.ok()
+}
+
+/// Query the sketch in `path` in place if `map` of it holds a single sketch, so that concurrent
+/// processes share it through the page cache. Otherwise, read and combine its sketches into
+/// `combined`.
+fn open_sketch<'a>(
+    path: &Path,
+    map: Option<&'a Mmap>,
+    combined: &'a mut Option<DuplicatesSketch>,
+    max_size: Option<Size>,
+) -> Result<DuplicatesSketchRef<'a>, Error> {
+    if let Some(Ok((sketch, []))) = map.map(|map| DuplicatesSketchRef::from_bytes(map)) {
+        return Ok(sketch);
+    }
+
+    let file = BufReader::new(File::open(path)?);
+    Ok(combined
+        .insert(combine_sketches(file, max_size, CombineOp::Union, false)?)
+        .as_sketch_ref())
+}
+
+/// Check that sketches with parameters `a` and `b` can be combined, naming the first that differs
+fn check_compatible(a: SketchParams, b: SketchParams) -> Result<(), Error> {
+    if a.indexing != b.indexing {
+        let name = |indexing| match indexing {
+            Indexing::Mask => "mask",
+            Indexing::FastRange => "fastrange",
+        };
+        return Err(anyhow!(
+            "Cannot combine sketches using different indexing ({} and {}). Sketches written by \
+             earlier versions use mask indexing, see --indexing",
+            name(a.indexing),
+            name(b.indexing)
+        ));
+    }
+
+    if a.counter_bits != b.counter_bits {
+        return Err(anyhow!(
+            "Cannot combine sketches with different counter bits ({} and {})",
+            a.counter_bits,
+            b.counter_bits
+        ));
+    }
+
+    if a.layout != b.layout {
+        let name = |layout| match layout {
+            Layout::Flat => "flat",
+            Layout::Blocked => "blocked",
+        };
+        return Err(anyhow!(
+            "Cannot combine sketches using different layouts ({} and {})",
+            name(a.layout),
+            name(b.layout)
+        ));
+    }
+
+    if a.counting != b.counting {
+        return Err(anyhow!(
+            "Cannot combine counting sketches with sketches not built with --counting"
+        ));
+    }
+
+    if a.probes != b.probes {
+        return Err(anyhow!(
+            "Cannot combine sketches using different numbers of probes ({} and {})",
+            a.probes,
+            b.probes
+        ));
+    }
+
+    debug_assert_eq!(a, b);
+    Ok(())
+}
+
+/// Read all sketches in `r` and combine them with `op`. With `presence`, each sketch is collapsed to
+/// the lines it contains first, so that counts are of sketches containing a line.
+///
+/// Sketches of different sizes are each folded to the smallest size among them, so all are read
+/// before any is combined.
+fn combine_sketches(
+    mut r: impl Read,
+    max_size: Option<Size>,
+    op: CombineOp,
+    presence: bool,
+) -> Result<DuplicatesSketch, Error> {
+    let max_bytes = max_size.map(|size| size.into::<Byte>().value() as u64);
+
+    // Sketches to fold and combine, in input order. Folding a union of sketches gives the union of
+    // the folded sketches, so a union only needs to keep one sketch per size.
+    let mut parts: Vec<DuplicatesSketch> = Vec::new();
+    while let Some(mut sketch) = DuplicatesSketch::deserialize_with_limit(&mut r, max_bytes)? {
+        if let Some(first) = parts.first() {
+            let (hash, other_hash) = (first.params().hash, sketch.params().hash);
+            if hash != other_hash {
+                return Err(anyhow!(
+                    "Cannot combine sketches using different hash functions ({} and {})",
+                    hash.name(),
+                    other_hash.name()
+                ));
+            }
+
+            if first.key_fingerprint() != sketch.key_fingerprint() {
+                return Err(anyhow!("Cannot combine sketches built with different keys"));
+            }
+
+            check_compatible(first.params(), sketch.params())?;
+        }
+
+        if presence {
+            sketch.collapse_to_presence();
+        }
+        match parts.iter_mut().find(|part| part.size() == sketch.size()) {
+            Some(part) if op == CombineOp::Union => part.merge(&sketch),
+            _ => parts.push(sketch),
+        }
+    }
+
+    let size = parts
+        .iter()
+        .map(DuplicatesSketch::size)
+        .min()
+        .ok_or_else(|| anyhow!("No sketches in input"))?;
+    for part in &mut parts {
+        if part.size() != size {
+            if !part.can_fold_to(size) {
+                return Err(anyhow!(
+                    "Cannot fold sketch of {} to {} to combine them",
+                    format_size(part.size()),
+                    format_size(size)
+                ));
+            }
+            part.fold_to(size);
+        }
+    }
+
+    let mut parts = parts.into_iter();
+    let mut sketch = parts.next().unwrap();
+    for part in parts {
+        match op {
+            CombineOp::Union => sketch.merge(&part),
+            CombineOp::Intersect => sketch.intersect(&part),
+            CombineOp::Subtract => sketch.subtract(&part),
+        }
+    }
+
+    Ok(sketch)
+}
+
+/// Bytes of input handed to a thread at a time
+const CHUNK_BYTES: usize = 1 << 20;
+
+/// Read `input` in chunks of whole lines ending in `sep`, except for the last line of the input,
+/// and pass them to `f` until it returns false.
+fn read_chunks(
+    mut input: impl Read,
+    sep: u8,
+    mut f: impl FnMut(Vec<u8>) -> io::Result<bool>,
+) -> io::Result<()> {
+    let mut chunk = Vec::with_capacity(CHUNK_BYTES);
+    loop {
+        if (&mut input)
+            .take(CHUNK_BYTES as u64)
+            .read_to_end(&mut chunk)?
+            == 0
+        {
+            if !chunk.is_empty() {
+                f(chunk)?;
+            }
+            return Ok(());
+        }
+
+        // Lines longer than a chunk are read until they end
+        if let Some(end) = chunk.iter().rposition(|&byte| byte == sep) {
+            let rest = chunk[end + 1..].to_vec();
+            chunk.truncate(end + 1);
+            if !f(mem::replace(&mut chunk, rest))? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Insert lines from `input` into `sketch` using `threads` threads, or with `remove`, remove them,
+/// giving the same sketch as inserting or removing them one by one.
+///
+/// 2-bit counters and counting sketches give the same sketch when lines are inserted in any order,
+/// so threads insert into a shared sketch. Other sketches, and removals, depend on the order of
+/// lines, so threads only hash lines, and the hashes are applied in input order by one more thread.
+fn insert_threaded(
+    sketch: DuplicatesSketch,
+    input: impl Read,
+    sep: u8,
+    threads: usize,
+    remove: bool,
+) -> Result<DuplicatesSketch, Error> {
+    if !remove && (sketch.counter_bits() == 2 || sketch.params().counting) {
+        let sketch = AtomicDuplicatesSketch::from(sketch);
+        thread::scope(|scope| {
+            let mut senders = Vec::new();
+            for _ in 0..threads {
+                let (sender, chunks) = sync_channel::<Vec<u8>>(2);
+                let sketch = &sketch;
+                scope.spawn(move || {
+                    for chunk in chunks {
+                        for line in chunk.split_inclusive(|&byte| byte == sep) {
+                            sketch.insert(line);
+                        }
+                    }
+                });
+                senders.push(sender);
+            }
+
+            let mut next = (0..threads).cycle();
+            read_chunks(input, sep, |chunk| {
+                Ok(senders[next.next().unwrap()].send(chunk).is_ok())
+            })
+        })?;
+
+        return Ok(sketch.into_sketch());
+    }
+
+    let mut sketch = sketch;
+    let hasher = sketch.line_hasher();
+    thread::scope(|scope| {
+        let mut senders = Vec::new();
+        let mut receivers = Vec::new();
+        for _ in 0..threads {
+            let (sender, chunks) = sync_channel::<Vec<u8>>(2);
+            let (hash_sender, hashes) = sync_channel::<Vec<LineHash>>(2);
+            scope.spawn(move || {
+                for chunk in chunks {
+                    let chunk_hashes = chunk
+                        .split_inclusive(|&byte| byte == sep)
+                        .map(|line| hasher.hash_line(line))
+                        .collect();
+                    if hash_sender.send(chunk_hashes).is_err() {
+                        break;
+                    }
                 }
+            });
+            senders.push(sender);
+            receivers.push(hashes);
+        }
+
+        // Chunks are handed to threads in turn, so taking their hashes in turn keeps input order
+        let sketch = &mut sketch;
+        scope.spawn(move || {
+            for hashes in receivers
+                .iter()
+                .cycle()
+                .map_while(|hashes| hashes.recv().ok())
+            {
+                for hash in hashes {
+                    if remove {
+                        sketch.remove_hash(hash);
+                    } else {
+                        sketch.insert_hash(hash);
+                    }
+                }
+            }
+        });
+
+        let mut next = (0..threads).cycle();
+        read_chunks(input, sep, |chunk| {
+            Ok(senders[next.next().unwrap()].send(chunk).is_ok())
+        })
+    })?;
 
-                sketch.merge(&to_merge);
+    Ok(sketch)
+}
+
+/// Results of chunks being filtered, in input order or in the order they are done
+enum Pending {
+    /// Each chunk gets its own result channel, which are waited on in turn
+    Ordered(VecDeque<Receiver<Vec<u8>>>),
+    /// Filter threads send all results to one channel, each holding a sender of their own
+    Unordered {
+        len: usize,
+        results: Receiver<Vec<u8>>,
+    },
+}
+
+impl Pending {
+    /// Create the pending results, and the sender to give filter threads if `!ordered`
+    fn new(ordered: bool) -> (Pending, Option<Sender<Vec<u8>>>) {
+        if ordered {
+            (Pending::Ordered(VecDeque::new()), None)
+        } else {
+            let (sender, results) = channel();
+            (Pending::Unordered { len: 0, results }, Some(sender))
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Pending::Ordered(results) => results.len(),
+            Pending::Unordered { len, .. } => *len,
+        }
+    }
+
+    /// Add a chunk, returning where its result is to be sent, unless filter threads send it to
+    /// their own sender
+    fn push(&mut self) -> Option<Sender<Vec<u8>>> {
+        match self {
+            Pending::Ordered(results) => {
+                let (sender, result) = channel();
+                results.push_back(result);
+                Some(sender)
+            }
+            Pending::Unordered { len, .. } => {
+                *len += 1;
+                None
+            }
+        }
+    }
+
+    /// Wait for the result of the next chunk
+    fn pop(&mut self) -> io::Result<Vec<u8>> {
+        let result = match self {
+            Pending::Ordered(results) => results.pop_front().unwrap().recv(),
+            Pending::Unordered { len, results } => {
+                *len -= 1;
+                results.recv()
             }
-            None => sketch = Some(to_merge),
         };
+        result.map_err(|_| io::Error::other("Filter thread stopped"))
+    }
+}
+
+/// Write the lines of `input` that `matches` to `output`, checking them on `threads` threads
+fn filter_lines(
+    mut input: impl BufRead,
+    output: &mut impl Write,
+    sep: u8,
+    threads: usize,
+    unordered: bool,
+    matches: impl Fn(&[u8]) -> bool + Sync,
+) -> Result<(), Error> {
+    if threads == 0 {
+        return Err(anyhow!("Number of threads cannot be 0"));
     }
 
-    sketch.ok_or_else(|| anyhow!("No sketches in input"))
+    if threads > 1 || unordered {
+        return filter_threaded(input, output, sep, threads, !unordered, matches);
+    }
+
+    let mut buf = Vec::new();
+    while input.read_until(sep, &mut buf)? != 0 {
+        if matches(&buf) {
+            output.write_all(&buf)?;
+        }
+        buf.clear();
+    }
+
+    Ok(())
+}
+
+/// Filter lines by `predicate` over the named `sketches`. Each line is hashed at most once for all
+/// sketches using the same hash function and key.
+#[allow(clippy::too_many_arguments)]
+fn filter_where(
+    input: impl BufRead,
+    output: &mut impl Write,
+    sketches: &[(String, PathBuf)],
+    predicate: &str,
+    key_file: Option<&Path>,
+    max_size: Option<Size>,
+    sep: u8,
+    threads: usize,
+    unordered: bool,
+) -> Result<(), Error> {
+    let names: Vec<String> = sketches.iter().map(|(name, _)| name.clone()).collect();
+    if let Some((ix, name)) = names
+        .iter()
+        .enumerate()
+        .find(|(ix, name)| names[..*ix].contains(name))
+    {
+        return Err(anyhow!(
+            "Sketch {} is named \"{}\" like another",
+            ix + 1,
+            name
+        ));
+    }
+    let predicate = Predicate::parse(predicate, &names)?;
+
+    let maps: Vec<_> = sketches.iter().map(|(_, path)| map_file(path)).collect();
+    let mut combined: Vec<_> = sketches.iter().map(|_| None).collect();
+    let mut opened = Vec::new();
+    for (((_, path), map), combined) in sketches.iter().zip(&maps).zip(&mut combined) {
+        let mut sketch = open_sketch(path, map.as_ref(), combined, max_size)?;
+        // Sketches built without a key are used along with keyed ones
+        let key_file = key_file.filter(|_| sketch.key_fingerprint().is_some());
+        if let Some(key) = sketch_key(sketch.key_fingerprint(), key_file)? {
+            sketch.set_key(key)?;
+        }
+        opened.push(sketch);
+    }
+
+    let mut checked = Ok(());
+    predicate.for_each_test(&mut |ix, condition| {
+        if checked.is_ok() {
+            checked = condition
+                .check(&opened[ix])
+                .map_err(|err| anyhow!("{} (sketch \"{}\")", err, names[ix]));
+        }
+    });
+    checked?;
+
+    let mut hashers: Vec<(HashFunction, Option<u64>, LineHasher)> = Vec::new();
+    let hasher_ixs: Vec<usize> = opened
+        .iter()
+        .map(|sketch| {
+            let id = (sketch.params().hash, sketch.key_fingerprint());
+            hashers
+                .iter()
+                .position(|&(hash, fingerprint, _)| (hash, fingerprint) == id)
+                .unwrap_or_else(|| {
+                    hashers.push((id.0, id.1, sketch.line_hasher()));
+                    hashers.len() - 1
+                })
+        })
+        .collect();
+
+    let matches = |line: &[u8]| {
+        let mut hashes = vec![None; hashers.len()];
+        predicate.eval(&mut |ix, condition| {
+            let hasher_ix = hasher_ixs[ix];
+            let hash =
+                *hashes[hasher_ix].get_or_insert_with(|| hashers[hasher_ix].2.hash_line(line));
+            condition.matches_hash(&opened[ix], hash)
+        })
+    };
+    filter_lines(input, output, sep, threads, unordered, matches)
+}
+
+/// Write the lines from `input` that `matches` to `output`, checking chunks of lines on `threads`
+/// threads.
+///
+/// Unless `ordered`, chunks are written as soon as they are done, so lines from different chunks
+/// can be output out of order.
+fn filter_threaded(
+    input: impl Read,
+    output: &mut impl Write,
+    sep: u8,
+    threads: usize,
+    ordered: bool,
+    matches: impl Fn(&[u8]) -> bool + Sync,
+) -> Result<(), Error> {
+    // Chunks being read, filtered or written at a time, which bounds memory use
+    let max_pending = 2 * threads;
+
+    let (job_sender, jobs) = sync_channel::<(Vec<u8>, Option<Sender<Vec<u8>>>)>(threads);
+    let jobs = Mutex::new(jobs);
+    let (mut pending, unordered) = Pending::new(ordered);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let (jobs, matches) = (&jobs, &matches);
+            let unordered = unordered.clone();
+            scope.spawn(move || {
+                loop {
+                    // Taking a job in its own statement releases the lock before filtering
+                    let job = jobs.lock().unwrap().recv();
+                    let Ok((chunk, result)) = job else { break };
+                    let result = result.as_ref().or(unordered.as_ref()).unwrap();
+                    let mut matched = Vec::with_capacity(chunk.len());
+                    for line in chunk.split_inclusive(|&byte| byte == sep) {
+                        if matches(line) {
+                            matched.extend_from_slice(line);
+                        }
+                    }
+
+                    if result.send(matched).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // Only filter threads may hold the unordered result channel, so that it closes if they stop
+        drop(unordered);
+        read_chunks(input, sep, |chunk| {
+            if pending.len() == max_pending {
+                output.write_all(&pending.pop()?)?;
+            }
+            Ok(job_sender.send((chunk, pending.push())).is_ok())
+        })?;
+
+        drop(job_sender);
+        while pending.len() > 0 {
+            output.write_all(&pending.pop()?)?;
+        }
+
+        Ok(())
+    })
+}
+
+fn write_sketch(sketch: &DuplicatesSketch, file: impl Write, compress: bool) -> Result<(), Error> {
+    if compress {
+        sketch.serialize_compressed(file)?;
+    } else {
+        sketch.serialize(file)?;
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Error> {
@@ -85,39 +1176,571 @@ fn main() -> Result<(), Error> {
     let mut stdout = BufWriter::new(stdout.lock());
 
     match opts {
-        Opt::Build { probes, size, zero_terminated } => {
+        Opt::Build {
+            probes,
+            size,
+            indexing,
+            counter_bits,
+            counting,
+            subtract_from,
+            layout,
+            hash,
+            key_file,
+            threads,
+            stats,
+            compress,
+            zero_terminated,
+        } => {
             if probes == 0 {
                 return Err(anyhow!("Number of probes cannot be 0"));
             }
 
+            if probes > MAX_PROBES {
+                return Err(anyhow!("Number of probes cannot exceed {}", MAX_PROBES));
+            }
+
+            if !COUNTER_BITS.contains(&counter_bits) {
+                return Err(anyhow!(
+                    "Bits per counter must be one of {:?}",
+                    COUNTER_BITS
+                ));
+            }
+
+            if counting && counter_bits == 2 {
+                return Err(anyhow!("Counting sketches need counters wider than 2 bits"));
+            }
+
             let size = size.into::<Byte>().value() as usize;
-            let mut sketch = DuplicatesSketch::new(probes, size);
+            let params = SketchParams {
+                probes,
+                counter_bits,
+                layout,
+                hash,
+                indexing,
+                counting,
+            };
+            let mut sketch = match (&subtract_from, key_file) {
+                (Some(path), key_file) => {
+                    let sketch = load_sketch(path, key_file.as_deref(), None)?;
+                    if !sketch.params().counting {
+                        return Err(anyhow!(
+                            "Lines can only be removed from sketches built with --counting"
+                        ));
+                    }
+                    sketch
+                }
+                (None, Some(key_file)) => {
+                    DuplicatesSketch::with_key(params, read_key(&key_file)?, size)
+                }
+                (None, None) => DuplicatesSketch::with_params(params, size),
+            };
+            let remove = subtract_from.is_some();
+
+            if threads == 0 {
+                return Err(anyhow!("Number of threads cannot be 0"));
+            }
 
             let sep = if zero_terminated { 0 } else { b'\n' };
-            let mut buf = Vec::new();
-            while stdin.read_until(sep, &mut buf)? != 0 {
-                sketch.insert(&buf);
-                buf.clear();
+            if threads > 1 {
+                sketch = insert_threaded(sketch, &mut stdin, sep, threads, remove)?;
+            } else {
+                let mut buf = Vec::new();
+                while stdin.read_until(sep, &mut buf)? != 0 {
+                    if remove {
+                        sketch.remove(&buf);
+                    } else {
+                        sketch.insert(&buf);
+                    }
+                    buf.clear();
+                }
+            }
+
+            if stats {
+                write_stats(&mut io::stderr(), &sketch.as_sketch_ref())?;
             }
 
-            sketch.serialize(stdout)?;
+            write_sketch(&sketch, stdout, compress)?;
+        }
+        Opt::Combine {
+            max_sketch_size,
+            op,
+            presence,
+            compress,
+        } => {
+            let sketch = combine_sketches(&mut stdin, max_sketch_size, op, presence)?;
+            write_sketch(&sketch, stdout, compress)?;
         }
-        Opt::Combine => {
-            combine_sketches(&mut stdin)?.serialize(stdout)?;
+        Opt::Shrink {
+            size,
+            max_sketch_size,
+            compress,
+        } => {
+            let mut sketch =
+                combine_sketches(&mut stdin, max_sketch_size, CombineOp::Union, false)?;
+            let max_size = size.into::<Byte>().value() as usize;
+            let fold_size = sketch.max_fold_size(max_size).ok_or_else(|| {
+                anyhow!(
+                    "Cannot fold sketch of {} to at most {}",
+                    format_size(sketch.size()),
+                    format_size(max_size)
+                )
+            })?;
+            sketch.fold_to(fold_size);
+            write_sketch(&sketch, stdout, compress)?;
         }
-        Opt::Filter { sketch, zero_terminated } => {
-            let sketch = combine_sketches(BufReader::new(File::open(sketch)?))?;
+        Opt::Filter {
+            sketch,
+            sketches,
+            predicate,
+            min_count,
+            uniques,
+            exact_count,
+            max_count,
+            present_in,
+            key_file,
+            max_sketch_size,
+            threads,
+            unordered,
+            zero_terminated,
+        } => {
+            let sep = if zero_terminated { 0 } else { b'\n' };
+            let sketch = match (predicate, sketch) {
+                (Some(predicate), _) => {
+                    return filter_where(
+                        &mut stdin,
+                        &mut stdout,
+                        &sketches,
+                        &predicate,
+                        key_file.as_deref(),
+                        max_sketch_size,
+                        sep,
+                        threads,
+                        unordered,
+                    );
+                }
+                (None, Some(sketch)) => sketch,
+                (None, None) => unreachable!("A sketch is required without --where"),
+            };
+
+            let (map, mut combined) = (map_file(&sketch), None);
+            let mut sketch = open_sketch(&sketch, map.as_ref(), &mut combined, max_sketch_size)?;
+            if let Some(key) = sketch_key(sketch.key_fingerprint(), key_file.as_deref())? {
+                sketch.set_key(key)?;
+            }
+
+            let condition = match (present_in, uniques, exact_count, max_count) {
+                (true, _, _, _) => Condition::Present,
+                (_, true, _, _) => Condition::Unique,
+                (_, _, Some(k), _) => Condition::Exactly(k),
+                (_, _, _, Some(k)) => Condition::AtMost(k),
+                _ => Condition::AtLeast(min_count),
+            };
+
+            condition.check(&sketch)?;
+
+            let matches = |line: &[u8]| condition.matches(&sketch, line);
+            filter_lines(&mut stdin, &mut stdout, sep, threads, unordered, matches)?;
+        }
+        Opt::Window {
+            lines,
+            seconds,
+            timestamp_field,
+            generations,
+            size,
+            probes,
+            zero_terminated,
+        } => {
+            if probes == 0 || probes > MAX_PROBES {
+                return Err(anyhow!(
+                    "Number of probes must be between 1 and {}",
+                    MAX_PROBES
+                ));
+            }
+
+            if generations == 0 {
+                return Err(anyhow!("Number of generations cannot be 0"));
+            }
+
+            if timestamp_field == Some(0) {
+                return Err(anyhow!("Fields are counted from 1"));
+            }
+
+            let params = SketchParams {
+                probes,
+                counter_bits: 2,
+                layout: Layout::Flat,
+                hash: HashFunction::Metro,
+                indexing: Indexing::FastRange,
+                counting: false,
+            };
+            let size = size.into::<Byte>().value() as usize;
+            // Keeping a generation more than the window is split into covers at least the whole
+            // window, even just after advancing
+            let mut window = WindowedSketch::with_params(params, size, generations + 1);
+
+            let generation_len = match (lines, seconds) {
+                (Some(lines), _) if lines > 0 => lines as f64 / generations as f64,
+                (_, Some(seconds)) if seconds > 0.0 => seconds / generations as f64,
+                _ => return Err(anyhow!("Window must be larger than 0")),
+            };
 
             let sep = if zero_terminated { 0 } else { b'\n' };
-            let mut buf = Vec::new();
+            let (mut buf, mut stripped) = (Vec::new(), Vec::new());
+            let mut line_ix = 0u64;
+            let mut generation = None;
             while stdin.read_until(sep, &mut buf)? != 0 {
-                if sketch.has_duplicate(&buf) {
+                // Lines are compared without their timestamp, which tells repeats apart
+                let mut key = &buf[..];
+                let position = match (lines, timestamp_field) {
+                    (Some(_), _) => Some(line_ix as f64),
+                    (None, Some(field)) => field_range(&buf, field).and_then(|range| {
+                        stripped.clear();
+                        stripped.extend_from_slice(&buf[..range.start]);
+                        stripped.extend_from_slice(&buf[range.end..]);
+                        key = &stripped;
+                        parse_time(&buf[range])
+                    }),
+                    (None, None) => {
+                        Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64())
+                    }
+                };
+                line_ix += 1;
+
+                if let Some(position) = position {
+                    let next = (position / generation_len) as u64;
+                    if let Some(current) = generation {
+                        let steps = next.saturating_sub(current).min(generations as u64 + 1);
+                        (0..steps).for_each(|_| window.advance());
+                    }
+                    generation = generation.max(Some(next));
+                }
+
+                let hash = window.hash_line(key);
+                window.insert_hash(hash);
+                if window.hash_estimate_count(hash) >= 2 {
                     stdout.write_all(&buf)?;
+                    // Repeats are output as they are found, as the input may be a live stream
+                    stdout.flush()?;
                 }
                 buf.clear();
             }
         }
+        Opt::Query {
+            sketch,
+            lines,
+            key_file,
+            max_sketch_size,
+            zero_terminated,
+        } => {
+            let sketch = load_sketch(&sketch, key_file.as_deref(), max_sketch_size)?;
+
+            // Lines are inserted into sketches with their delimiter, so queries need it as well
+            let sep = if zero_terminated { 0 } else { b'\n' };
+            let mut buf = Vec::new();
+            let mut query = |buf: &mut Vec<u8>| -> Result<(), Error> {
+                write!(stdout, "{}\t", sketch.estimate_count(buf))?;
+                if buf.last() != Some(&sep) {
+                    buf.push(sep);
+                }
+                stdout.write_all(buf)?;
+                Ok(())
+            };
+
+            if lines.is_empty() {
+                while stdin.read_until(sep, &mut buf)? != 0 {
+                    query(&mut buf)?;
+                    buf.clear();
+                }
+            } else {
+                for line in lines {
+                    buf.extend_from_slice(line.as_bytes());
+                    buf.push(sep);
+                    query(&mut buf)?;
+                    buf.clear();
+                }
+            }
+        }
+        Opt::Stats {
+            sketch,
+            json,
+            max_sketch_size,
+        } => {
+            let (map, mut combined) = (map_file(&sketch), None);
+            let sketch = open_sketch(&sketch, map.as_ref(), &mut combined, max_sketch_size)?;
+            if json {
+                write_stats_json(&mut stdout, &sketch)?;
+            } else {
+                write_stats(&mut stdout, &sketch)?;
+            }
+        }
+        Opt::Keygen => {
+            writeln!(stdout, "{}", HashKey::random()?.to_hex())?;
+        }
+        Opt::Plan {
+            distinct,
+            target_fpr,
+            memory,
+        } => {
+            let plan = match (target_fpr, memory) {
+                (Some(target), _) => {
+                    if !(target > 0.0 && target < 1.0) {
+                        return Err(anyhow!(
+                            "Target false positive rate must be between 0 and 1"
+                        ));
+                    }
+                    plan_for_rate(distinct, target).ok_or_else(|| {
+                        anyhow!("No sketch small enough to address reaches the target false positive rate")
+                    })?
+                }
+                (None, Some(memory)) => {
+                    plan_for_size(distinct, memory.into::<Byte>().value() as usize)
+                }
+                (None, None) => unreachable!(),
+            };
+
+            writeln!(
+                stdout,
+                "Size: {} bytes ({})",
+                plan.size,
+                format_size(plan.size)
+            )?;
+            writeln!(stdout, "Probes: {}", plan.probes)?;
+            writeln!(
+                stdout,
+                "Expected fraction of lines occurring once output by filter: {:.6}",
+                plan.false_positive_rate
+            )?;
+            writeln!(
+                stdout,
+                "Build with: sketch-duplicates build --size {}B --probes {}",
+                plan.size, plan.probes
+            )?;
+        }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines repeating with different periods, spanning several chunks
+    fn lines() -> Vec<u8> {
+        let mut input = Vec::new();
+        for i in 0..200_000 {
+            writeln!(input, "line {}", i % 70_000).unwrap();
+        }
+        input
+    }
+
+    fn chunks(input: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        read_chunks(input, b'\n', |chunk| {
+            chunks.push(chunk);
+            Ok(true)
+        })
+        .unwrap();
+        chunks
+    }
+
+    #[test]
+    fn read_chunks_boundaries() {
+        assert!(chunks(b"").is_empty());
+        assert_eq!(chunks(b"a\nb"), vec![b"a\n".to_vec(), b"b".to_vec()]);
+
+        let mut input = vec![b'a'; CHUNK_BYTES + CHUNK_BYTES / 2];
+        input.push(b'\n');
+        input.extend(lines());
+        input.extend_from_slice(b"unterminated");
+        let chunks = chunks(&input);
+
+        assert!(chunks.len() > 2);
+        // The long line is not split
+        assert!(chunks[0].len() > CHUNK_BYTES + CHUNK_BYTES / 2);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert_eq!(chunk.last(), Some(&b'\n'));
+        }
+        assert_eq!(chunks.last().unwrap(), b"unterminated");
+        assert_eq!(chunks.concat(), input);
+    }
+
+    #[test]
+    fn read_chunks_stop() {
+        let mut calls = 0;
+        read_chunks(&lines()[..], b'\n', |_| {
+            calls += 1;
+            Ok(false)
+        })
+        .unwrap();
+        assert_eq!(calls, 1);
+    }
+
+    fn insert_sequential(sketch: &mut DuplicatesSketch, input: &[u8], remove: bool) {
+        for line in input.split_inclusive(|&byte| byte == b'\n') {
+            if remove {
+                sketch.remove(line);
+            } else {
+                sketch.insert(line);
+            }
+        }
+    }
+
+    #[test]
+    fn insert_threaded_matches_sequential() {
+        let input = lines();
+        let counting = SketchParams {
+            counting: true,
+            ..DuplicatesSketch::with_counter_bits(3, 8, 0).params()
+        };
+        let sketches: [&dyn Fn() -> DuplicatesSketch; 3] = [
+            &|| DuplicatesSketch::new(3, 1 << 16),
+            &|| DuplicatesSketch::with_counter_bits(3, 8, 1 << 16),
+            &|| DuplicatesSketch::with_params(counting, 1 << 16),
+        ];
+
+        for sketch in &sketches {
+            let mut expected = sketch();
+            insert_sequential(&mut expected, &input, false);
+            for threads in [1, 3] {
+                let threaded = insert_threaded(sketch(), &input[..], b'\n', threads, false);
+                assert_eq!(threaded.unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn remove_threaded_matches_sequential() {
+        let input = lines();
+        let removed = &input[..input.len() / 2];
+        let params = SketchParams {
+            counting: true,
+            ..DuplicatesSketch::with_counter_bits(3, 4, 0).params()
+        };
+        let mut built = DuplicatesSketch::with_params(params, 1 << 16);
+        insert_sequential(&mut built, &input, false);
+
+        let mut expected = DuplicatesSketch::with_params(params, 1 << 16);
+        insert_sequential(&mut expected, &input, false);
+        insert_sequential(&mut expected, removed, true);
+
+        let threaded = insert_threaded(built, removed, b'\n', 3, true).unwrap();
+        assert_eq!(threaded, expected);
+    }
+
+    fn filter(input: &[u8], threads: usize, ordered: bool) -> Vec<u8> {
+        let mut output = Vec::new();
+        filter_threaded(input, &mut output, b'\n', threads, ordered, |line| {
+            line.ends_with(b"7\n")
+        })
+        .unwrap();
+        output
+    }
+
+    #[test]
+    fn filter_ordered() {
+        let input = lines();
+        let expected: Vec<u8> = input
+            .split_inclusive(|&byte| byte == b'\n')
+            .filter(|line| line.ends_with(b"7\n"))
+            .flatten()
+            .copied()
+            .collect();
+
+        for threads in [1, 3] {
+            assert_eq!(filter(&input, threads, true), expected);
+        }
+    }
+
+    #[test]
+    fn filter_unordered() {
+        let input = lines();
+        let mut expected: Vec<_> = filter(&input, 1, true)
+            .split_inclusive(|&byte| byte == b'\n')
+            .map(<[u8]>::to_vec)
+            .collect();
+        expected.sort();
+
+        let output = filter(&input, 3, false);
+        let mut lines: Vec<_> = output
+            .split_inclusive(|&byte| byte == b'\n')
+            .map(<[u8]>::to_vec)
+            .collect();
+        lines.sort();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "a scoped thread panicked")]
+    fn filter_unordered_panic() {
+        let mut output = Vec::new();
+        let _ = filter_threaded(&lines()[..], &mut output, b'\n', 2, false, |line| {
+            if line == b"line 0\n" {
+                panic!("matches panicked");
+            }
+            true
+        });
+    }
+
+    /// Sketches of `sizes` bytes, each of the lines `i` to `i + 1000` for its index `i`
+    fn sized_sketches(sizes: &[usize], counter_bits: u32) -> Vec<DuplicatesSketch> {
+        let params = SketchParams {
+            counter_bits,
+            indexing: Indexing::FastRange,
+            ..Default::default()
+        };
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                let mut sketch = DuplicatesSketch::with_params(params, size);
+                for line in i * 100..i * 100 + 1000 {
+                    sketch.insert(&line.to_le_bytes());
+                }
+                sketch
+            })
+            .collect()
+    }
+
+    fn combine(sketches: &[DuplicatesSketch], op: CombineOp) -> DuplicatesSketch {
+        let mut buf = Vec::new();
+        for sketch in sketches {
+            sketch.serialize(&mut buf).unwrap();
+        }
+        combine_sketches(&buf[..], None, op, false).unwrap()
+    }
+
+    #[test]
+    fn combine_folds_to_smallest() {
+        for op in [CombineOp::Union, CombineOp::Intersect, CombineOp::Subtract] {
+            let sizes = [12 << 10, 8 << 10, 4 << 10];
+            let mut expected = sized_sketches(&sizes, 4);
+            for sketch in &mut expected {
+                sketch.fold_to(4 << 10);
+            }
+            let expected = combine(&expected, op);
+            assert_eq!(expected.size(), 4 << 10);
+
+            // Neither 12 nor 8 KiB folds to the other, so this only works folding to 4 KiB
+            assert_eq!(combine(&sized_sketches(&sizes, 4), op), expected);
+
+            // Only the first sketch is special to subtracting, so the others can come in any order
+            let mut reversed = sized_sketches(&sizes, 4);
+            if op == CombineOp::Subtract {
+                reversed[1..].reverse();
+            } else {
+                reversed.reverse();
+            }
+            assert_eq!(combine(&reversed, op), expected);
+        }
+    }
+
+    #[test]
+    fn combine_unfoldable() {
+        let mut buf = Vec::new();
+        for sketch in sized_sketches(&[12 << 10, 8 << 10], 2) {
+            sketch.serialize(&mut buf).unwrap();
+        }
+        assert!(combine_sketches(&buf[..], None, CombineOp::Union, false).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Saturating merges and other operations on sketch words.
+//!
+//! Counters never straddle words, so words can be merged in any grouping. Besides the scalar
+//! reference implementation, this merges pairs of words as `u64`, or 8 words at a time with AVX2
+//! when the CPU supports it. Intersections and differences are rare enough to be done one counter
+//! at a time.
+
+use crate::{Word, WORD_BITS, WORD_MASK};
+
+/// Merge the counters in `b` into `a`, saturating each `counter_bits` wide counter.
+pub(crate) fn merge_words(a: &mut [Word], b: &[Word], counter_bits: u32) {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // Safety: AVX2 support was just checked
+            return unsafe { avx2::merge_words(a, b, counter_bits) };
+        }
+    }
+
+    merge_words_u64(a, b, counter_bits);
+}
+
+/// Merge one word at a time. 2-bit counters use the same encoding as inserts, where 3 means two or
+/// more occurrences. Wider counters hold the count itself.
+pub(crate) fn merge_words_scalar(a: &mut [Word], b: &[Word], counter_bits: u32) {
+    if counter_bits == 2 {
+        for (a, b) in a.iter_mut().zip(b.iter()) {
+            *a |= *b | (*a & WORD_MASK).wrapping_add(*b & WORD_MASK);
+        }
+    } else {
+        let mask = (1 << counter_bits) - 1;
+        for (a, b) in a.iter_mut().zip(b.iter()) {
+            *a = (0..WORD_BITS)
+                .step_by(counter_bits as usize)
+                .fold(0, |word, bit_ix| {
+                    let sum = (*a >> bit_ix & mask) + (*b >> bit_ix & mask);
+                    word | sum.min(mask) << bit_ix
+                });
+        }
+    }
+}
+
+fn merge_words_u64(a: &mut [Word], b: &[Word], counter_bits: u32) {
+    let split = a.len() & !1;
+    let (a, a_rest) = a.split_at_mut(split);
+    let (b, b_rest) = b.split_at(split);
+
+    let high = repeat(high_bits(counter_bits));
+    for (a, b) in a.chunks_exact_mut(2).zip(b.chunks_exact(2)) {
+        let x = u64::from(a[0]) | u64::from(a[1]) << WORD_BITS;
+        let y = u64::from(b[0]) | u64::from(b[1]) << WORD_BITS;
+
+        let merged = if counter_bits == 2 {
+            let mask = repeat(WORD_MASK);
+            x | y | (x & mask).wrapping_add(y & mask)
+        } else {
+            saturating_add_u64(x, y, high, counter_bits)
+        };
+
+        a[0] = merged as Word;
+        a[1] = (merged >> WORD_BITS) as Word;
+    }
+
+    merge_words_scalar(a_rest, b_rest, counter_bits);
+}
+
+/// Set every nonzero counter in `words` to 1.
+pub(crate) fn presence_words(words: &mut [Word], counter_bits: u32) {
+    let low = high_bits(counter_bits) >> (counter_bits - 1);
+    for word in words {
+        // Or all bits of each counter into its lowest bit. Bits shifted in from the next counter
+        // only reach the higher bits, which are masked off.
+        let mut x = *word;
+        let mut shift = 1;
+        while shift < counter_bits {
+            x |= x >> shift;
+            shift *= 2;
+        }
+        *word = x & low;
+    }
+}
+
+/// Set each counter in `a` to the smaller of it and the counter in `b`.
+pub(crate) fn intersect_words(a: &mut [Word], b: &[Word], counter_bits: u32) {
+    combine_counters(a, b, counter_bits, u32::min);
+}
+
+/// Subtract each counter in `b` from the counter in `a`, stopping at zero.
+pub(crate) fn subtract_words(a: &mut [Word], b: &[Word], counter_bits: u32) {
+    combine_counters(a, b, counter_bits, u32::saturating_sub);
+}
+
+/// Combine the counts in `a` and `b` with `f`, which must not return more than its first argument.
+///
+/// 2-bit counters are counts of 0, 1 and 2 or more, where 2 is stored as 3 like inserts do.
+fn combine_counters(a: &mut [Word], b: &[Word], counter_bits: u32, f: impl Fn(u32, u32) -> u32) {
+    assert_eq!(a.len(), b.len());
+
+    let mask = (1 << counter_bits) - 1;
+    let max = if counter_bits == 2 { 2 } else { mask };
+    for (a, b) in a.iter_mut().zip(b.iter()) {
+        *a = (0..WORD_BITS)
+            .step_by(counter_bits as usize)
+            .fold(0, |word, bit_ix| {
+                let count = f(
+                    (*a >> bit_ix & mask).min(max),
+                    (*b >> bit_ix & mask).min(max),
+                );
+                let counter = if count == max { mask } else { count };
+                word | counter << bit_ix
+            });
+    }
+}
+
+/// Mask of the highest bit of every counter in a word
+fn high_bits(counter_bits: u32) -> Word {
+    (0..WORD_BITS)
+        .step_by(counter_bits as usize)
+        .fold(0, |mask, bit_ix| mask | 1 << (bit_ix + counter_bits - 1))
+}
+
+fn repeat(word: Word) -> u64 {
+    u64::from(word) | u64::from(word) << WORD_BITS
+}
+
+/// Add all counters in `x` and `y` at once, saturating those that overflow.
+///
+/// Without their high bits, counters can be added without carrying into the next counter. The
+/// high bits are then added back in with xor, and counters carrying out of their high bit are set
+/// to all ones.
+fn saturating_add_u64(x: u64, y: u64, high: u64, counter_bits: u32) -> u64 {
+    let low_sum = (x & !high) + (y & !high);
+    let sum = low_sum ^ (x ^ y) & high;
+    let carry = (x & y | (x | y) & low_sum) & high;
+    sum | (carry << 1).wrapping_sub(carry >> (counter_bits - 1))
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use super::{high_bits, merge_words_scalar};
+    use crate::{Word, WORD_MASK};
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 8;
+
+    /// Same as `saturating_add_u64`, but on 32-bit lanes.
+    #[target_feature(enable = "avx2")]
+    unsafe fn saturating_add(x: __m256i, y: __m256i, high: __m256i, shift: __m128i) -> __m256i {
+        let low_sum = _mm256_add_epi32(_mm256_andnot_si256(high, x), _mm256_andnot_si256(high, y));
+        let sum = _mm256_xor_si256(low_sum, _mm256_and_si256(_mm256_xor_si256(x, y), high));
+        let carry = _mm256_and_si256(
+            _mm256_or_si256(
+                _mm256_and_si256(x, y),
+                _mm256_and_si256(_mm256_or_si256(x, y), low_sum),
+            ),
+            high,
+        );
+        _mm256_or_si256(
+            sum,
+            _mm256_sub_epi32(_mm256_slli_epi32(carry, 1), _mm256_srl_epi32(carry, shift)),
+        )
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn merge_words(a: &mut [Word], b: &[Word], counter_bits: u32) {
+        let split = a.len() / LANES * LANES;
+        let (a, a_rest) = a.split_at_mut(split);
+        let (b, b_rest) = b.split_at(split);
+
+        let mask = _mm256_set1_epi32(WORD_MASK as i32);
+        let high = _mm256_set1_epi32(high_bits(counter_bits) as i32);
+        let shift = _mm_cvtsi32_si128(counter_bits as i32 - 1);
+
+        for (a, b) in a.chunks_exact_mut(LANES).zip(b.chunks_exact(LANES)) {
+            let a_ptr = a.as_mut_ptr() as *mut __m256i;
+            let x = _mm256_loadu_si256(a_ptr);
+            let y = _mm256_loadu_si256(b.as_ptr() as *const __m256i);
+
+            let merged = if counter_bits == 2 {
+                let carry = _mm256_add_epi32(_mm256_and_si256(x, mask), _mm256_and_si256(y, mask));
+                _mm256_or_si256(_mm256_or_si256(x, y), carry)
+            } else {
+                saturating_add(x, y, high, shift)
+            };
+
+            _mm256_storeu_si256(a_ptr, merged);
+        }
+
+        merge_words_scalar(a_rest, b_rest, counter_bits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::COUNTER_BITS;
+    use proptest::{collection::vec, prelude::*};
+
+    fn word_pairs() -> impl Strategy<Value = (Vec<Word>, Vec<Word>)> {
+        (0..100usize).prop_flat_map(|len| (vec(any::<Word>(), len), vec(any::<Word>(), len)))
+    }
+
+    proptest! {
+        #[test]
+        fn merge_identical(
+            (a, b) in word_pairs(),
+            counter_bits in proptest::sample::select(COUNTER_BITS),
+        ) {
+            let mut expected = a.clone();
+            merge_words_scalar(&mut expected, &b, counter_bits);
+
+            let mut merged = a.clone();
+            merge_words(&mut merged, &b, counter_bits);
+            prop_assert_eq!(&merged, &expected);
+
+            let mut merged = a;
+            merge_words_u64(&mut merged, &b, counter_bits);
+            prop_assert_eq!(&merged, &expected);
+        }
+
+        #[test]
+        fn intersect_subtract(
+            (a, b) in word_pairs(),
+            counter_bits in proptest::sample::select(COUNTER_BITS),
+        ) {
+            let (mut min, mut difference) = (a.clone(), a.clone());
+            intersect_words(&mut min, &b, counter_bits);
+            subtract_words(&mut difference, &b, counter_bits);
+
+            let mask = (1 << counter_bits) - 1;
+            let max = if counter_bits == 2 { 2 } else { mask };
+            let count = |word: Word, bit_ix: u32| (word >> bit_ix & mask).min(max);
+            for i in 0..a.len() {
+                for bit_ix in (0..WORD_BITS).step_by(counter_bits as usize) {
+                    let (x, y) = (count(a[i], bit_ix), count(b[i], bit_ix));
+                    prop_assert_eq!(count(min[i], bit_ix), x.min(y));
+                    prop_assert_eq!(count(difference[i], bit_ix), x.saturating_sub(y));
+                }
+            }
+        }
+
+        #[test]
+        fn presence(
+            words in vec(any::<Word>(), 0..100usize),
+            counter_bits in proptest::sample::select(COUNTER_BITS),
+        ) {
+            let mut present = words.clone();
+            presence_words(&mut present, counter_bits);
+
+            let mask = (1 << counter_bits) - 1;
+            for (word, present) in words.iter().zip(&present) {
+                for bit_ix in (0..WORD_BITS).step_by(counter_bits as usize) {
+                    let expected = u32::from(word >> bit_ix & mask != 0);
+                    prop_assert_eq!(present >> bit_ix & mask, expected);
+                }
+            }
+        }
+    }
+}
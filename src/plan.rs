@@ -0,0 +1,136 @@
+//! Choosing sketch sizes and probes.
+//!
+//! A line inserted once is output by `filter` when each of its probed counters was also hit by some
+//! other line. With `n` distinct lines, `k` probes and `m` counters, a counter is missed by all
+//! other lines with probability `e^(-kn/m)`, so unique lines leak through with probability
+//! `(1 - e^(-kn/m))^k`, as in a Bloom filter. This is for 2-bit counters and the flat layout.
+
+use crate::{Word, WORD_BITS};
+use std::mem::size_of;
+
+const COUNTERS_PER_WORD: f64 = (WORD_BITS / 2) as f64;
+
+/// Most probes considered when planning. More probes only help with absurdly low rates.
+const MAX_PLAN_PROBES: u32 = 64;
+
+/// Size and number of probes for a sketch, and the resulting false positive rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plan {
+    /// Size of the sketch in bytes.
+    pub size: usize,
+    pub probes: u32,
+    /// Expected fraction of lines occurring once that `filter` outputs as duplicates.
+    pub false_positive_rate: f64,
+}
+
+/// Expected fraction of lines occurring once that are reported as duplicates by a sketch of `size`
+/// bytes with 2-bit counters and `probes` probes, after inserting `distinct` distinct lines.
+pub fn false_positive_rate(distinct: u64, size: usize, probes: u32) -> f64 {
+    let counters = (size / size_of::<Word>()).max(1) as f64 * COUNTERS_PER_WORD;
+    let load = f64::from(probes) * distinct.saturating_sub(1) as f64 / counters;
+    (-(-load).exp_m1()).powi(probes as i32)
+}
+
+/// Plan the smallest sketch for `distinct` distinct lines that has a false positive rate of at
+/// most `target`, which must be between 0 and 1.
+///
+/// Returns `None` if no sketch small enough to address meets `target`.
+pub fn plan_for_rate(distinct: u64, target: f64) -> Option<Plan> {
+    assert!(target > 0.0 && target < 1.0);
+
+    (1..=MAX_PLAN_PROBES)
+        .filter_map(|probes| {
+            // Solve (1 - e^(-kn/m))^k = target for m, then round up to whole words
+            let load = -(-target.powf(1.0 / f64::from(probes))).ln_1p();
+            let hits = f64::from(probes) * distinct.saturating_sub(1) as f64;
+            let words = (hits / load / COUNTERS_PER_WORD).ceil().max(1.0);
+            // Casting saturates, so sizes that do not fit only fail to multiply
+            let size = (words as usize).checked_mul(size_of::<Word>())?;
+            Some(Plan {
+                size,
+                probes,
+                false_positive_rate: false_positive_rate(distinct, size, probes),
+            })
+        })
+        .filter(|plan| plan.false_positive_rate <= target)
+        .min_by_key(|plan| plan.size)
+}
+
+/// Plan the number of probes giving the lowest false positive rate for `distinct` distinct lines
+/// in a sketch of `size` bytes.
+pub fn plan_for_size(distinct: u64, size: usize) -> Plan {
+    let size = (size / size_of::<Word>()).max(1) * size_of::<Word>();
+
+    (1..=MAX_PLAN_PROBES)
+        .map(|probes| Plan {
+            size,
+            probes,
+            false_positive_rate: false_positive_rate(distinct, size, probes),
+        })
+        .min_by(|a, b| a.false_positive_rate.total_cmp(&b.false_positive_rate))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DuplicatesSketch, Indexing, SketchParams};
+
+    #[test]
+    fn rate() {
+        assert_eq!(false_positive_rate(0, 1024, 4), 0.0);
+        assert!(false_positive_rate(1000, 1024, 4) > false_positive_rate(1000, 4096, 4));
+        assert!(false_positive_rate(1000, 1024, 4) < false_positive_rate(2000, 1024, 4));
+    }
+
+    #[test]
+    fn plans() {
+        for &distinct in &[1, 1000, 1_000_000, 1_000_000_000] {
+            for &target in &[0.5, 0.01, 0.0001] {
+                let plan = plan_for_rate(distinct, target).unwrap();
+                assert!(plan.false_positive_rate <= target);
+
+                // A word less would not do with the same probes
+                let smaller = plan.size - size_of::<Word>();
+                assert!(
+                    smaller == 0 || false_positive_rate(distinct, smaller, plan.probes) > target
+                );
+
+                // Other numbers of probes only do worse in the same size
+                let best = plan_for_size(distinct, plan.size);
+                assert!(best.false_positive_rate <= plan.false_positive_rate);
+            }
+        }
+    }
+
+    #[test]
+    fn unreachable() {
+        assert!(plan_for_rate(u64::MAX, 1e-300).is_none());
+    }
+
+    #[test]
+    fn matches_sketch() {
+        let distinct = 20_000u32;
+        let plan = plan_for_rate(u64::from(distinct), 0.05).unwrap();
+        let params = SketchParams {
+            probes: plan.probes,
+            indexing: Indexing::FastRange,
+            ..Default::default()
+        };
+        let mut sketch = DuplicatesSketch::with_params(params, plan.size);
+        for i in 0..distinct {
+            sketch.insert(&i.to_le_bytes());
+        }
+
+        let leaked = (0..distinct)
+            .filter(|i| sketch.has_duplicate(&i.to_le_bytes()))
+            .count();
+        let rate = leaked as f64 / f64::from(distinct);
+        assert!(
+            (rate - plan.false_positive_rate).abs() < 0.01,
+            "{} {:?}",
+            rate,
+            plan
+        );
+    }
+}
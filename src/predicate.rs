@@ -0,0 +1,315 @@
+//! Predicates over several named sketches, as given to `filter --where`.
+//!
+//! ```text
+//! or    := and ("||" and)*
+//! and   := not ("&&" not)*
+//! not   := "!" not | "(" or ")" | test
+//! test  := "dup" "(" name ")" | "seen" "(" name ")" | "count" "(" name ")" cmp number
+//! cmp   := ">=" | ">" | "<=" | "<" | "==" | "!="
+//! ```
+
+use crate::Condition;
+use anyhow::{anyhow, Error};
+
+/// A boolean expression of conditions on the count of a line in several sketches.
+#[derive(Debug, PartialEq)]
+pub enum Predicate {
+    /// Condition on the sketch at an index into the names the predicate was parsed with
+    Test(usize, Condition),
+    Not(Box<Predicate>),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+impl Predicate {
+    /// Parse `source`, where sketches are referred to by `names`.
+    pub fn parse(source: &str, names: &[String]) -> Result<Predicate, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            names,
+        };
+        let predicate = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(predicate),
+            Some(token) => Err(anyhow!("Unexpected {} in predicate", token)),
+        }
+    }
+
+    /// Evaluate the predicate, checking conditions on sketches with `check` as needed.
+    pub fn eval(&self, check: &mut impl FnMut(usize, Condition) -> bool) -> bool {
+        match self {
+            Predicate::Test(ix, condition) => check(*ix, *condition),
+            Predicate::Not(predicate) => !predicate.eval(check),
+            Predicate::And(a, b) => a.eval(check) && b.eval(check),
+            Predicate::Or(a, b) => a.eval(check) || b.eval(check),
+        }
+    }
+
+    /// Call `f` with every condition in the predicate.
+    pub fn for_each_test(&self, f: &mut impl FnMut(usize, Condition)) {
+        match self {
+            Predicate::Test(ix, condition) => f(*ix, *condition),
+            Predicate::Not(predicate) => predicate.for_each_test(f),
+            Predicate::And(a, b) | Predicate::Or(a, b) => {
+                a.for_each_test(f);
+                b.for_each_test(f);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(u32),
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "\"{}\"", ident),
+            Token::Number(number) => write!(f, "\"{}\"", number),
+            Token::Symbol(symbol) => write!(f, "\"{}\"", symbol),
+        }
+    }
+}
+
+/// Symbols of the language, with those that are prefixes of others last
+const SYMBOLS: &[&str] = &["&&", "||", ">=", "<=", "==", "!=", "!", "(", ")", ">", "<"];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let number = rest[..len]
+                .parse()
+                .map_err(|_| anyhow!("Number \"{}\" in predicate is too large", &rest[..len]))?;
+            tokens.push(Token::Number(number));
+            len
+        } else if is_name_char(c) {
+            let len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_owned()));
+            len
+        } else {
+            return Err(anyhow!("Unexpected \"{}\" in predicate", c));
+        };
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// Whether `c` can be part of a sketch name
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    names: &'a [String],
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Result<Token, Error> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unexpected end of predicate"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// Consume the next token if it is `symbol`
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found =
+            matches!(self.tokens.get(self.pos), Some(Token::Symbol(found)) if *found == symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), Error> {
+        match self.next()? {
+            Token::Symbol(found) if found == symbol => Ok(()),
+            token => Err(anyhow!(
+                "Expected \"{}\" in predicate, found {}",
+                symbol,
+                token
+            )),
+        }
+    }
+
+    fn or(&mut self) -> Result<Predicate, Error> {
+        let mut predicate = self.and()?;
+        while self.eat("||") {
+            predicate = Predicate::Or(Box::new(predicate), Box::new(self.and()?));
+        }
+        Ok(predicate)
+    }
+
+    fn and(&mut self) -> Result<Predicate, Error> {
+        let mut predicate = self.not()?;
+        while self.eat("&&") {
+            predicate = Predicate::And(Box::new(predicate), Box::new(self.not()?));
+        }
+        Ok(predicate)
+    }
+
+    fn not(&mut self) -> Result<Predicate, Error> {
+        if self.eat("!") {
+            Ok(Predicate::Not(Box::new(self.not()?)))
+        } else if self.eat("(") {
+            let predicate = self.or()?;
+            self.expect(")")?;
+            Ok(predicate)
+        } else {
+            self.test()
+        }
+    }
+
+    fn test(&mut self) -> Result<Predicate, Error> {
+        let function = match self.next()? {
+            Token::Ident(function) => function,
+            token => return Err(anyhow!("Unexpected {} in predicate", token)),
+        };
+        self.expect("(")?;
+        let ix = match self.next()? {
+            Token::Ident(name) => self
+                .names
+                .iter()
+                .position(|other| *other == name)
+                .ok_or_else(|| anyhow!("No sketch named \"{}\"", name))?,
+            token => {
+                return Err(anyhow!(
+                    "Expected sketch name in predicate, found {}",
+                    token
+                ))
+            }
+        };
+        self.expect(")")?;
+
+        let condition = match function.as_str() {
+            "dup" => Condition::AtLeast(2),
+            "seen" => Condition::Present,
+            "count" => return self.comparison(ix),
+            _ => return Err(anyhow!("Unknown function \"{}\" in predicate", function)),
+        };
+        Ok(Predicate::Test(ix, condition))
+    }
+
+    fn comparison(&mut self, ix: usize) -> Result<Predicate, Error> {
+        let op = match self.next()? {
+            Token::Symbol(op) => op,
+            token => return Err(anyhow!("Expected comparison in predicate, found {}", token)),
+        };
+        let k = match self.next()? {
+            Token::Number(k) => k,
+            token => return Err(anyhow!("Expected number in predicate, found {}", token)),
+        };
+
+        let test = |condition| Ok(Predicate::Test(ix, condition));
+        match op {
+            ">=" => test(Condition::AtLeast(k)),
+            ">" => test(Condition::AtLeast(k.saturating_add(1))),
+            "<=" => test(Condition::AtMost(k)),
+            "<" if k > 0 => test(Condition::AtMost(k - 1)),
+            "<" => Err(anyhow!("Counts are never less than 0")),
+            "==" => test(Condition::Exactly(k)),
+            "!=" => Ok(Predicate::Not(Box::new(Predicate::Test(
+                ix,
+                Condition::Exactly(k),
+            )))),
+            _ => Err(anyhow!(
+                "Expected comparison in predicate, found \"{}\"",
+                op
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        vec!["a".to_owned(), "b".to_owned()]
+    }
+
+    #[test]
+    fn parse() {
+        let predicate = Predicate::parse("dup(a) && !seen(b) || count(b) > 3", &names()).unwrap();
+        assert_eq!(
+            predicate,
+            Predicate::Or(
+                Box::new(Predicate::And(
+                    Box::new(Predicate::Test(0, Condition::AtLeast(2))),
+                    Box::new(Predicate::Not(Box::new(Predicate::Test(
+                        1,
+                        Condition::Present
+                    )))),
+                )),
+                Box::new(Predicate::Test(1, Condition::AtLeast(4))),
+            )
+        );
+
+        let predicate = Predicate::parse("dup(a) && (seen(b) || count(a)<=1)", &names()).unwrap();
+        let mut tests = Vec::new();
+        predicate.for_each_test(&mut |ix, condition| tests.push((ix, condition)));
+        assert_eq!(
+            tests,
+            vec![
+                (0, Condition::AtLeast(2)),
+                (1, Condition::Present),
+                (0, Condition::AtMost(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn eval() {
+        let predicate =
+            Predicate::parse("dup(a) && !(seen(b) || count(a) == 5)", &names()).unwrap();
+        let mut checked = Vec::new();
+        let matches = predicate.eval(&mut |ix, condition| {
+            checked.push(ix);
+            match condition {
+                Condition::AtLeast(2) => true,
+                Condition::Present => false,
+                _ => true,
+            }
+        });
+
+        assert!(!matches);
+        assert_eq!(checked, vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn errors() {
+        for source in &[
+            "",
+            "dup(c)",
+            "dup(a",
+            "dup(a) &&",
+            "dup(a) seen(b)",
+            "size(a)",
+            "count(a)",
+            "count(a) < 0",
+            "count(a) >= 99999999999",
+            "dup(a) & seen(b)",
+        ] {
+            assert!(Predicate::parse(source, &names()).is_err(), "{}", source);
+        }
+    }
+}
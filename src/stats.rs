@@ -0,0 +1,139 @@
+//! Statistics of the counters in a sketch.
+//!
+//! Estimates follow the same model as `plan`: each distinct line hits `k` of the `m` counters, so
+//! the number of distinct lines hitting a counter is Poisson distributed with mean `λ = kn/m`. The
+//! fraction of zero counters `p0 = e^-λ` then gives `n`, and the fraction of counters at 1, which
+//! were hit by a single line occurring once, gives the number of such lines.
+
+use crate::{DuplicatesSketch, DuplicatesSketchRef, WORD_BITS};
+
+/// Statistics of the counters in a sketch, see `DuplicatesSketchRef::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SketchStats {
+    /// Number of counters in the sketch.
+    pub counters: u64,
+    /// Number of counters holding each count from 0 to `max_count`. Saturated counters are counted
+    /// at `max_count`, so for 2-bit counters, the last entry is for counts of 2 or more.
+    pub histogram: Vec<u64>,
+    /// Fraction of counters that are not zero.
+    pub fill_ratio: f64,
+    /// Estimated number of distinct lines inserted. Infinite if all counters are in use.
+    pub distinct_lines: f64,
+    /// Estimated number of distinct lines inserted more than once.
+    pub duplicated_lines: f64,
+    /// Predicted fraction of lines inserted once that are reported as duplicates.
+    pub false_positive_rate: f64,
+}
+
+impl DuplicatesSketchRef<'_> {
+    /// Compute statistics of the counters of the sketch, to tell how full it is.
+    ///
+    /// Estimates assume the flat layout. For counters wider than 2 bits, conservative update
+    /// leaves some counters of lines occurring more than once at 1, so duplicated lines are
+    /// underestimated.
+    pub fn stats(&self) -> SketchStats {
+        let counter_bits = self.params.counter_bits;
+        let mut histogram = vec![0; self.max_count() as usize + 1];
+        for word_ix in 0..self.words.len() {
+            let word = self.words.get(word_ix);
+            for bit_ix in (0..WORD_BITS).step_by(counter_bits as usize) {
+                histogram[self.params.counter(word, bit_ix) as usize] += 1;
+            }
+        }
+
+        let counters: u64 = histogram.iter().sum();
+        let (m, k) = (counters as f64, f64::from(self.params.probes));
+        let p0 = histogram[0] as f64 / m;
+        let p1 = histogram[1] as f64 / m;
+
+        let distinct_lines = (1.0 / p0).ln() * m / k;
+        let unique_lines = if p0 > 0.0 { p1 * m / (k * p0) } else { 0.0 };
+        let fill_ratio = 1.0 - p0;
+
+        SketchStats {
+            counters,
+            histogram,
+            fill_ratio,
+            distinct_lines,
+            duplicated_lines: (distinct_lines - unique_lines).max(0.0),
+            false_positive_rate: fill_ratio.powi(self.params.probes as i32),
+        }
+    }
+}
+
+impl DuplicatesSketch {
+    /// See `DuplicatesSketchRef::stats`.
+    pub fn stats(&self) -> SketchStats {
+        self.as_sketch_ref().stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Indexing, SketchParams};
+
+    #[test]
+    fn empty() {
+        let stats = DuplicatesSketch::new(4, 1024).stats();
+        assert_eq!(stats.counters, 1024 * 4);
+        assert_eq!(stats.histogram, vec![1024 * 4, 0, 0]);
+        assert_eq!(stats.fill_ratio, 0.0);
+        assert_eq!(stats.distinct_lines, 0.0);
+        assert_eq!(stats.duplicated_lines, 0.0);
+        assert_eq!(stats.false_positive_rate, 0.0);
+    }
+
+    #[test]
+    fn estimates() {
+        let params = SketchParams {
+            probes: 3,
+            indexing: Indexing::FastRange,
+            ..Default::default()
+        };
+        let mut sketch = DuplicatesSketch::with_params(params, 40_000);
+        for i in 0..20_000u32 {
+            sketch.insert(&i.to_le_bytes());
+            if i % 4 == 0 {
+                sketch.insert(&i.to_le_bytes());
+            }
+        }
+
+        let stats = sketch.stats();
+        assert_eq!(stats.histogram.iter().sum::<u64>(), stats.counters);
+        assert!(
+            (stats.distinct_lines / 20_000.0 - 1.0).abs() < 0.05,
+            "{:?}",
+            stats
+        );
+        assert!(
+            (stats.duplicated_lines / 5_000.0 - 1.0).abs() < 0.1,
+            "{:?}",
+            stats
+        );
+
+        let leaked = (20_000..40_000u32)
+            .filter(|i| sketch.has_count_at_least(&i.to_le_bytes(), 1))
+            .count();
+        let rate = leaked as f64 / 20_000.0;
+        assert!(
+            (rate - stats.false_positive_rate).abs() < 0.01,
+            "{} {:?}",
+            rate,
+            stats
+        );
+    }
+
+    #[test]
+    fn wide() {
+        let mut sketch = DuplicatesSketch::with_counter_bits(1, 8, 64);
+        for _ in 0..1000 {
+            sketch.insert(b"asdf");
+        }
+
+        let stats = sketch.stats();
+        assert_eq!(stats.histogram.len(), 256);
+        assert_eq!(stats.histogram[255], 1);
+        assert_eq!(stats.histogram[0], stats.counters - 1);
+    }
+}
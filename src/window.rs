@@ -0,0 +1,131 @@
+//! Sketches of the most recent lines of a stream.
+
+use crate::{DuplicatesSketch, LineHash, SketchParams};
+use std::collections::VecDeque;
+
+/// A sketch of only the most recently inserted lines.
+///
+/// Lines are inserted into the newest of several segments, each a `DuplicatesSketch`. `advance`
+/// starts a new segment, dropping the oldest once there are `segments` of them, so that the sketch
+/// covers the lines inserted since the last `segments - 1` calls to `advance`, and those since.
+/// Queries add up the counts of a line in all segments.
+#[derive(Debug)]
+pub struct WindowedSketch {
+    params: SketchParams,
+    size: usize,
+    max_segments: usize,
+    /// Live segments, oldest first
+    segments: VecDeque<DuplicatesSketch>,
+}
+
+impl WindowedSketch {
+    /// Create a sketch of at most `segments` segments of `size` bytes each.
+    pub fn with_params(params: SketchParams, size: usize, segments: usize) -> WindowedSketch {
+        assert!(segments > 0);
+
+        WindowedSketch {
+            params,
+            size,
+            max_segments: segments,
+            segments: VecDeque::from(vec![DuplicatesSketch::with_params(params, size)]),
+        }
+    }
+
+    pub fn params(&self) -> SketchParams {
+        self.params
+    }
+
+    fn newest(&self) -> &DuplicatesSketch {
+        self.segments.back().unwrap()
+    }
+
+    /// Start a new segment, dropping the oldest if there are as many as the sketch can hold.
+    pub fn advance(&mut self) {
+        if self.segments.len() < self.max_segments {
+            let segment = DuplicatesSketch::with_params(self.params, self.size);
+            self.segments.push_back(segment);
+        } else {
+            // Reuse the memory of the oldest segment
+            let mut segment = self.segments.pop_front().unwrap();
+            segment.words.fill(0);
+            self.segments.push_back(segment);
+        }
+    }
+
+    /// Count an occurrence of `buf` in the newest segment.
+    #[inline]
+    pub fn insert(&mut self, buf: &[u8]) {
+        self.insert_hash(self.hash_line(buf));
+    }
+
+    /// `insert` for a line hashed ahead of time, see `hash_line`.
+    #[inline]
+    pub fn insert_hash(&mut self, hash: LineHash) {
+        self.segments.back_mut().unwrap().insert_hash(hash);
+    }
+
+    /// Hash `buf` for this sketch.
+    #[inline]
+    pub fn hash_line(&self, buf: &[u8]) -> LineHash {
+        self.newest().hash_line(buf)
+    }
+
+    /// Check whether `buf` has probably been inserted at least twice into the live segments.
+    #[inline]
+    pub fn has_duplicate(&self, buf: &[u8]) -> bool {
+        self.estimate_count(buf) >= 2
+    }
+
+    /// Estimate how many times `buf` has been inserted into the live segments.
+    ///
+    /// Like `DuplicatesSketch::estimate_count`, this never underestimates, except that counts
+    /// saturate in each segment.
+    #[inline]
+    pub fn estimate_count(&self, buf: &[u8]) -> u32 {
+        self.hash_estimate_count(self.hash_line(buf))
+    }
+
+    /// `estimate_count` for a line hashed ahead of time, see `hash_line`.
+    #[inline]
+    pub fn hash_estimate_count(&self, hash: LineHash) -> u32 {
+        self.segments
+            .iter()
+            .map(|segment| segment.as_sketch_ref().hash_estimate_count(hash))
+            .fold(0, u32::saturating_add)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(segments: usize) -> WindowedSketch {
+        WindowedSketch::with_params(DuplicatesSketch::new(4, 1024).params(), 1024, segments)
+    }
+
+    #[test]
+    fn across_segments() {
+        let mut window = window(3);
+        window.insert(b"a");
+        window.advance();
+        assert!(!window.has_duplicate(b"a"));
+        window.insert(b"a");
+        window.advance();
+        window.insert(b"a");
+        assert!(window.has_duplicate(b"a"));
+        assert_eq!(window.estimate_count(b"a"), 3);
+    }
+
+    #[test]
+    fn expire() {
+        let mut window = window(2);
+        window.insert(b"a");
+        window.insert(b"a");
+        window.advance();
+        assert!(window.has_duplicate(b"a"));
+
+        window.advance();
+        assert_eq!(window.estimate_count(b"a"), 0);
+        assert_eq!(window.segments.len(), 2);
+    }
+}